@@ -0,0 +1,433 @@
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    config::snapshots::{Snapshot, SnapshotMap},
+    htp_test::{TestID, TestPriority, PRIORITY_MANUAL_ONESHOT},
+    inventory::SnapshotExists,
+    orchestrator::OrchestratorHandle,
+    resource_ledger::{LedgerEntry, ResourceLedger},
+    running_test_map::RunningTestMapEntry,
+};
+
+// A small HTTP/JSON API for controlling the orchestrator from the network.
+//
+//   GET    /tests                         every test that has not terminated yet
+//   POST   /tests                         submit a test, see SubmitRequest
+//   GET    /tests/:id                     where a test is, or its result once it has terminated
+//   GET    /tests/:id/logs?after=N        the test's output, after line N if given
+//   POST   /tests/:id/cancel              cancel a queued or running test
+//   GET    /resources                     devices and apparatuses and who holds them
+//   POST   /resources/:kind/:name/release take a stuck device or apparatus from its holders
+//   GET    /inventory/snapshots           internal/snapshots.json5
+//   POST   /inventory/snapshots/:device   add a Snapshot to a device
+//   DELETE /inventory/snapshots/:device?path=...
+//
+// Errors are returned as { "error": "..." } with a matching status code.
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubmitRequest {
+    pub test_group: String,
+    pub test_name: String,
+    // A priority level, see htp_test.rs. Defaults to a manual one-shot run.
+    #[serde(default)]
+    pub priority: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Submitted {
+    pub t_id: TestID,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunningTest {
+    pub t_id: TestID,
+    pub test_group: String,
+    pub test_name: String,
+    pub stage: String,
+    pub stage_entered: chrono::DateTime<chrono::Utc>,
+    pub cancel_requested: bool,
+}
+
+impl From<&RunningTestMapEntry> for RunningTest {
+    fn from(entry: &RunningTestMapEntry) -> Self {
+        Self {
+            t_id: entry.ver.clone(),
+            test_group: entry.id.0.clone(),
+            test_name: entry.id.1.clone(),
+            stage: entry.stage.clone(),
+            stage_entered: entry.entry_time.into(),
+            cancel_requested: entry.cancel_requested,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum TestStatus {
+    InProgress(RunningTest),
+    // `result` is the test's document from the `tests` index
+    Terminated { result: Value },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CancelRequested {
+    pub t_id: TestID,
+    // The stage the test was in when it was cancelled
+    pub stage: String,
+}
+
+// One line of a test's output, from the `logs` index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLine {
+    pub log_line: u64,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub data: String,
+    pub is_stderr: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LogsQuery {
+    pub after: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Holder {
+    pub t_id: TestID,
+    pub exclusive: bool,
+    // The wrapper apparatus this was locked as a part of
+    pub wrapped_by: Option<String>,
+}
+
+impl From<&LedgerEntry> for Holder {
+    fn from(entry: &LedgerEntry) -> Self {
+        Self {
+            t_id: entry.testid.clone(),
+            exclusive: entry.exclusive,
+            wrapped_by: entry.wrapped_by.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceState {
+    pub name: String,
+    pub device_type: String,
+    pub connected_apparatuses: Vec<String>,
+    pub holders: Vec<Holder>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApparatusState {
+    pub name: String,
+    pub is_exclusively_locked: bool,
+    pub wrapped_apparatuses: Vec<String>,
+    pub holders: Vec<Holder>,
+}
+
+// Sorted by name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resources {
+    pub devices: Vec<DeviceState>,
+    pub apparatuses: Vec<ApparatusState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceKind {
+    Device,
+    Apparatus,
+}
+
+// The locks that were taken away. Their tests are not stopped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Released {
+    pub released_from: Vec<Holder>,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new<M: Into<String>>(status: StatusCode, message: M) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+pub fn router(handle: OrchestratorHandle) -> Router {
+    Router::new()
+        .route("/tests", get(list_tests).post(submit_test))
+        .route("/tests/:t_id", get(get_test))
+        .route("/tests/:t_id/logs", get(test_logs))
+        .route("/tests/:t_id/cancel", post(cancel_test))
+        .route("/resources", get(resources))
+        .route("/resources/:kind/:name/release", post(release_resource))
+        .route("/inventory/snapshots", get(snapshots))
+        .route(
+            "/inventory/snapshots/:device",
+            post(add_snapshot).delete(remove_snapshot),
+        )
+        .with_state(handle)
+}
+
+// Serves the API on `addr` until the orchestrator shuts down
+pub async fn serve(addr: SocketAddr, handle: OrchestratorHandle) -> anyhow::Result<()> {
+    let shutdown = handle.shutdown().clone();
+    log::info!("Serving the API on http://{}", addr);
+    axum::Server::try_bind(&addr)
+        .with_context(|| format!("Binding the API to {}", addr))?
+        .serve(router(handle).into_make_service())
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+    Ok(())
+}
+
+async fn list_tests(State(handle): State<OrchestratorHandle>) -> Json<Vec<RunningTest>> {
+    let map = handle.test_map().lock().unwrap();
+    Json(map.map.iter().map(RunningTest::from).collect())
+}
+
+async fn submit_test(
+    State(handle): State<OrchestratorHandle>,
+    Json(request): Json<SubmitRequest>,
+) -> Result<(StatusCode, Json<Submitted>), ApiError> {
+    let priority = match request.priority {
+        None => PRIORITY_MANUAL_ONESHOT,
+        Some(level) => TestPriority::from_level(level).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Unknown priority level {}", level),
+            )
+        })?,
+    };
+    if handle.shutdown().is_cancelled() {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The orchestrator is shutting down",
+        ));
+    }
+    // Unknown tests are accepted here and fail validation like any other
+    let t_id = handle.submit((request.test_group, request.test_name), priority)?;
+    Ok((StatusCode::CREATED, Json(Submitted { t_id })))
+}
+
+async fn get_test(
+    State(handle): State<OrchestratorHandle>,
+    Path(t_id): Path<TestID>,
+) -> Result<Json<TestStatus>, ApiError> {
+    if let Some(entry) = handle.test_map().lock().unwrap().get(&t_id) {
+        return Ok(Json(TestStatus::InProgress(entry.into())));
+    }
+    let mut found = handle.stats_backend().find_by_test("tests", &t_id).await?;
+    match found.pop() {
+        Some(result) => Ok(Json(TestStatus::Terminated { result })),
+        // A test that just terminated may not have been indexed yet
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No test {}", t_id),
+        )),
+    }
+}
+
+async fn cancel_test(
+    State(handle): State<OrchestratorHandle>,
+    Path(t_id): Path<TestID>,
+) -> Result<(StatusCode, Json<CancelRequested>), ApiError> {
+    match handle.cancel(&t_id) {
+        Some(stage) => Ok((StatusCode::ACCEPTED, Json(CancelRequested { t_id, stage }))),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No queued or running test {}", t_id),
+        )),
+    }
+}
+
+async fn test_logs(
+    State(handle): State<OrchestratorHandle>,
+    Path(t_id): Path<TestID>,
+    Query(query): Query<LogsQuery>,
+) -> Result<Json<Vec<LogLine>>, ApiError> {
+    let documents = handle.stats_backend().find_by_test("logs", &t_id).await?;
+    Ok(Json(log_lines(documents, query.after)?))
+}
+
+// Ordered by line number, the order the test logged them in
+fn log_lines(documents: Vec<Value>, after: Option<u64>) -> anyhow::Result<Vec<LogLine>> {
+    let mut lines = Vec::new();
+    for document in documents {
+        let line: LogLine = serde_json::from_value(document).context("Malformed log line")?;
+        if after.is_none_or(|after| line.log_line > after) {
+            lines.push(line);
+        }
+    }
+    lines.sort_by_key(|line| line.log_line);
+    Ok(lines)
+}
+
+async fn resources(State(handle): State<OrchestratorHandle>) -> Result<Json<Resources>, ApiError> {
+    let config = handle.lab_config()?;
+    let ledger = handle.ledger().lock();
+    let holders = |ledger: &ResourceLedger, name: &str| -> Vec<Holder> {
+        ledger.holders(name).into_iter().map(Holder::from).collect()
+    };
+    let mut resources = Resources {
+        devices: config
+            .devices
+            .iter()
+            .map(|(name, device)| DeviceState {
+                name: name.clone(),
+                device_type: device.device_type.clone(),
+                connected_apparatuses: device.connected_apparatuses.clone(),
+                holders: holders(&ledger.devices, name),
+            })
+            .collect(),
+        apparatuses: config
+            .apparatuses
+            .iter()
+            .map(|(name, apparatus)| ApparatusState {
+                name: name.clone(),
+                is_exclusively_locked: apparatus.is_exclusively_locked,
+                wrapped_apparatuses: apparatus.wrapped_apparatuses.clone(),
+                holders: holders(&ledger.apparatuses, name),
+            })
+            .collect(),
+    };
+    resources.devices.sort_by(|a, b| a.name.cmp(&b.name));
+    resources.apparatuses.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(resources))
+}
+
+async fn release_resource(
+    State(handle): State<OrchestratorHandle>,
+    Path((kind, name)): Path<(ResourceKind, String)>,
+) -> Result<Json<Released>, ApiError> {
+    let config = handle.lab_config()?;
+    let known = match kind {
+        ResourceKind::Device => config.devices.contains_key(&name),
+        ResourceKind::Apparatus => config.apparatuses.contains_key(&name),
+    };
+    if !known {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No {:?} {}", kind, name).to_lowercase(),
+        ));
+    }
+    let released = match kind {
+        ResourceKind::Device => handle.ledger().force_release_device(&name),
+        ResourceKind::Apparatus => handle.ledger().force_release_apparatus(&name),
+    };
+    Ok(Json(Released {
+        released_from: released.iter().map(Holder::from).collect(),
+    }))
+}
+
+async fn snapshots(
+    State(handle): State<OrchestratorHandle>,
+) -> Result<Json<SnapshotMap>, ApiError> {
+    Ok(Json(handle.inventory().snapshots()?))
+}
+
+async fn add_snapshot(
+    State(handle): State<OrchestratorHandle>,
+    Path(device): Path<String>,
+    Json(snapshot): Json<Snapshot>,
+) -> Result<StatusCode, ApiError> {
+    if !handle.lab_config()?.devices.contains_key(&device) {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No device {}", device),
+        ));
+    }
+    match handle.inventory().add_snapshot(&device, snapshot) {
+        Ok(()) => Ok(StatusCode::CREATED),
+        Err(err) if err.is::<SnapshotExists>() => {
+            Err(ApiError::new(StatusCode::CONFLICT, err.to_string()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn remove_snapshot(
+    State(handle): State<OrchestratorHandle>,
+    Path(device): Path<String>,
+    Query(snapshot): Query<Snapshot>,
+) -> Result<StatusCode, ApiError> {
+    if handle.inventory().remove_snapshot(&device, &snapshot)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("Device {} has no snapshot {}", device, snapshot.path),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_lines() {
+        let line = |n: u64| {
+            json!({
+                "t_id": "abc",
+                "timestamp": "2023-05-01T12:00:00Z",
+                "log_line": n,
+                "data": format!("line {}", n),
+                "is_stderr": false,
+            })
+        };
+        let lines = log_lines(vec![line(2), line(0), line(1)], None).unwrap();
+        let numbers: Vec<u64> = lines.iter().map(|line| line.log_line).collect();
+        assert_eq!(numbers, vec![0, 1, 2]);
+        let lines = log_lines(vec![line(2), line(0), line(1)], Some(0)).unwrap();
+        assert_eq!(lines[0].data, "line 1");
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn test_status_serialization() {
+        let status = TestStatus::Terminated {
+            result: json!({ "t_id": "abc", "passed": true }),
+        };
+        let value = serde_json::to_value(&status).unwrap();
+        assert_eq!(
+            value,
+            json!({ "state": "terminated", "result": { "t_id": "abc", "passed": true } })
+        );
+        assert_eq!(serde_json::from_value::<TestStatus>(value).unwrap(), status);
+
+        let request: SubmitRequest =
+            serde_json::from_str(r#"{ "test_group": "general", "test_name": "simpleconn" }"#)
+                .unwrap();
+        assert_eq!(request.priority, None);
+    }
+}
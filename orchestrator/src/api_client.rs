@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Context};
+use reqwest::{Method, RequestBuilder, Url};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    api::{
+        CancelRequested, LogLine, Released, ResourceKind, Resources, RunningTest, SubmitRequest,
+        Submitted, TestStatus,
+    },
+    config::snapshots::{Snapshot, SnapshotMap},
+};
+
+// Talks to the API in api.rs, for the htp CLI
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    base: Url,
+    http: reqwest::Client,
+}
+
+impl ApiClient {
+    // Accepts "host:port" as well as a full url
+    pub fn new(addr: &str) -> anyhow::Result<Self> {
+        let base = if addr.contains("://") {
+            Url::parse(addr)
+        } else {
+            Url::parse(&format!("http://{}", addr))
+        }
+        .with_context(|| format!("Invalid orchestrator address {}", addr))?;
+        Ok(Self {
+            base,
+            http: reqwest::Client::new(),
+        })
+    }
+    pub async fn submit(&self, request: &SubmitRequest) -> anyhow::Result<Submitted> {
+        self.send(self.request(Method::POST, &["tests"])?.json(request))
+            .await
+    }
+    pub async fn tests(&self) -> anyhow::Result<Vec<RunningTest>> {
+        self.send(self.request(Method::GET, &["tests"])?).await
+    }
+    pub async fn test(&self, t_id: &str) -> anyhow::Result<TestStatus> {
+        self.send(self.request(Method::GET, &["tests", t_id])?)
+            .await
+    }
+    // Lines after `after`, or every line if None
+    pub async fn logs(&self, t_id: &str, after: Option<u64>) -> anyhow::Result<Vec<LogLine>> {
+        let mut request = self.request(Method::GET, &["tests", t_id, "logs"])?;
+        if let Some(after) = after {
+            request = request.query(&[("after", after)]);
+        }
+        self.send(request).await
+    }
+    pub async fn cancel(&self, t_id: &str) -> anyhow::Result<CancelRequested> {
+        self.send(self.request(Method::POST, &["tests", t_id, "cancel"])?)
+            .await
+    }
+    pub async fn resources(&self) -> anyhow::Result<Resources> {
+        self.send(self.request(Method::GET, &["resources"])?).await
+    }
+    pub async fn release(&self, kind: ResourceKind, name: &str) -> anyhow::Result<Released> {
+        let kind = match kind {
+            ResourceKind::Device => "device",
+            ResourceKind::Apparatus => "apparatus",
+        };
+        self.send(self.request(Method::POST, &["resources", kind, name, "release"])?)
+            .await
+    }
+    pub async fn snapshots(&self) -> anyhow::Result<SnapshotMap> {
+        self.send(self.request(Method::GET, &["inventory", "snapshots"])?)
+            .await
+    }
+    pub async fn add_snapshot(&self, device: &str, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let request = self.request(Method::POST, &["inventory", "snapshots", device])?;
+        self.send_empty(request.json(snapshot)).await
+    }
+    pub async fn remove_snapshot(&self, device: &str, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let request = self.request(Method::DELETE, &["inventory", "snapshots", device])?;
+        self.send_empty(request.query(snapshot)).await
+    }
+    // Each segment is escaped, so ids and names can hold anything
+    fn request(&self, method: Method, segments: &[&str]) -> anyhow::Result<RequestBuilder> {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("{} cannot be a base url", self.base))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(self.http.request(method, url))
+    }
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> anyhow::Result<T> {
+        let response = Self::check(request).await?;
+        response.json().await.context("Unexpected response")
+    }
+    async fn send_empty(&self, request: RequestBuilder) -> anyhow::Result<()> {
+        Self::check(request).await?;
+        Ok(())
+    }
+    // Turns the API's { "error": "..." } bodies into errors
+    async fn check(request: RequestBuilder) -> anyhow::Result<reqwest::Response> {
+        let response = request
+            .send()
+            .await
+            .context("Unable to reach the orchestrator")?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body: Value = response.json().await.unwrap_or_default();
+        match body["error"].as_str() {
+            Some(error) => Err(anyhow!("{}", error)),
+            None => Err(anyhow!("The orchestrator responded with {}", status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, routing::get, Json, Router};
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_client_errors() {
+        let app = Router::new()
+            .route("/tests", get(|| async { Json(json!([])) }))
+            .route(
+                "/tests/:t_id",
+                get(|| async {
+                    (
+                        StatusCode::NOT_FOUND,
+                        Json(json!({ "error": "No test abc" })),
+                    )
+                }),
+            );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let client = ApiClient::new(&addr.to_string()).unwrap();
+        assert!(client.tests().await.unwrap().is_empty());
+        let err = client.test("abc").await.unwrap_err();
+        assert_eq!(err.to_string(), "No test abc");
+        let err = client.resources().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The orchestrator responded with 404 Not Found"
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use orchestrator::{
+    api::{Holder, ResourceKind, RunningTest, SubmitRequest, TestStatus},
+    api_client::ApiClient,
+    config::snapshots::Snapshot,
+};
+
+// How often --follow and --watch ask the orchestrator for news
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Control a running HTP orchestrator
+#[derive(Parser)]
+#[command(name = "htp")]
+struct Cli {
+    /// The orchestrator's api_addr
+    #[arg(long, env = "HTP_SERVER", default_value = "127.0.0.1:9185")]
+    server: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Submit a test and print its id
+    Submit {
+        test_group: String,
+        test_name: String,
+        /// 0 (admin) through 4 (canary). Lower runs first. Defaults to 1
+        #[arg(long)]
+        priority: Option<usize>,
+        /// Print the test's output until it terminates, then its result
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// List the tests that have not terminated yet
+    Status {
+        /// Keep refreshing the list
+        #[arg(short, long)]
+        watch: bool,
+    },
+    /// Show where a test is, or its result once it has terminated
+    Result { t_id: String },
+    /// Print a test's output
+    Logs {
+        t_id: String,
+        /// Keep printing new output until the test terminates
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Cancel queued or running tests
+    Cancel {
+        #[arg(required = true)]
+        t_ids: Vec<String>,
+    },
+    /// List devices and apparatuses and the tests holding them
+    Resources,
+    /// Take a stuck device or apparatus from the tests holding it.
+    /// The tests themselves keep running.
+    Release {
+        #[arg(value_enum)]
+        kind: ResourceKind,
+        name: String,
+    },
+    /// Manage the device snapshots in internal/snapshots.json5
+    Snapshots {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommand {
+    /// List snapshots, for every device or just one
+    List {
+        device: Option<String>,
+    },
+    Add {
+        device: String,
+        path: String,
+    },
+    Remove {
+        device: String,
+        path: String,
+    },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let client = ApiClient::new(&cli.server)?;
+    match cli.command {
+        Command::Submit {
+            test_group,
+            test_name,
+            priority,
+            follow,
+        } => {
+            let submitted = client
+                .submit(&SubmitRequest {
+                    test_group,
+                    test_name,
+                    priority,
+                })
+                .await?;
+            println!("{}", submitted.t_id);
+            if follow {
+                print_logs(&client, &submitted.t_id, true).await?;
+                print_status(&client.test(&submitted.t_id).await?)?;
+            }
+        }
+        Command::Status { watch } => loop {
+            let tests = client.tests().await?;
+            if watch {
+                // Clear the terminal and start from the top
+                print!("\x1b[2J\x1b[H");
+            }
+            print_tests(&tests);
+            if !watch {
+                break;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        },
+        Command::Result { t_id } => print_status(&client.test(&t_id).await?)?,
+        Command::Logs { t_id, follow } => print_logs(&client, &t_id, follow).await?,
+        Command::Cancel { t_ids } => {
+            for t_id in t_ids {
+                let cancelled = client.cancel(&t_id).await?;
+                println!("Cancelled {} in stage {}", cancelled.t_id, cancelled.stage);
+            }
+        }
+        Command::Resources => {
+            let resources = client.resources().await?;
+            println!("{:<24}  {:<16}  HELD BY", "DEVICE", "TYPE");
+            for device in &resources.devices {
+                println!(
+                    "{:<24}  {:<16}  {}",
+                    device.name,
+                    device.device_type,
+                    holders(&device.holders)
+                );
+            }
+            println!();
+            println!("{:<24}  {:<16}  HELD BY", "APPARATUS", "EXCLUSIVE");
+            for apparatus in &resources.apparatuses {
+                println!(
+                    "{:<24}  {:<16}  {}",
+                    apparatus.name,
+                    apparatus.is_exclusively_locked,
+                    holders(&apparatus.holders)
+                );
+            }
+        }
+        Command::Release { kind, name } => {
+            let released = client.release(kind, &name).await?;
+            if released.released_from.is_empty() {
+                println!("{} was not held", name);
+            } else {
+                println!(
+                    "Released {} from {}",
+                    name,
+                    holders(&released.released_from)
+                );
+            }
+        }
+        Command::Snapshots { command } => match command {
+            SnapshotCommand::List { device } => {
+                for (name, snapshots) in client.snapshots().await? {
+                    if device.as_ref().is_some_and(|device| *device != name) {
+                        continue;
+                    }
+                    for snapshot in snapshots.snapshots {
+                        println!("{:<24}  {}", name, snapshot.path);
+                    }
+                }
+            }
+            SnapshotCommand::Add { device, path } => {
+                client.add_snapshot(&device, &Snapshot { path }).await?
+            }
+            SnapshotCommand::Remove { device, path } => {
+                client.remove_snapshot(&device, &Snapshot { path }).await?
+            }
+        },
+    }
+    Ok(())
+}
+
+fn print_tests(tests: &[RunningTest]) {
+    println!("{:<36}  {:<32}  {:<10}  IN STAGE", "ID", "TEST", "STAGE");
+    let now = chrono::offset::Utc::now();
+    for test in tests {
+        let in_stage = (now - test.stage_entered).to_std().unwrap_or_default();
+        println!(
+            "{:<36}  {:<32}  {:<10}  {}{}",
+            test.t_id,
+            format!("{}/{}", test.test_group, test.test_name),
+            test.stage,
+            format_duration(in_stage),
+            if test.cancel_requested {
+                " (cancelling)"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+fn print_status(status: &TestStatus) -> anyhow::Result<()> {
+    match status {
+        TestStatus::InProgress(test) => print_tests(std::slice::from_ref(test)),
+        TestStatus::Terminated { result } => {
+            println!("{}", serde_json::to_string_pretty(result)?)
+        }
+    }
+    Ok(())
+}
+
+// With `follow`, keeps going until the test has terminated
+async fn print_logs(client: &ApiClient, t_id: &str, follow: bool) -> anyhow::Result<()> {
+    let mut after = None;
+    let mut seen_running = false;
+    loop {
+        // Checked before reading so that the last lines are not missed
+        let finished = !follow
+            || match client.test(t_id).await {
+                Ok(TestStatus::InProgress(_)) => {
+                    seen_running = true;
+                    false
+                }
+                Ok(TestStatus::Terminated { .. }) => true,
+                // It terminated but its result has not been indexed yet
+                Err(_) if seen_running => true,
+                Err(err) => return Err(err),
+            };
+        for line in client.logs(t_id, after).await? {
+            if line.is_stderr {
+                eprintln!("{}", line.data);
+            } else {
+                println!("{}", line.data);
+            }
+            after = Some(line.log_line);
+        }
+        if finished {
+            return Ok(());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+fn holders(holders: &[Holder]) -> String {
+    if holders.is_empty() {
+        return "-".into();
+    }
+    holders
+        .iter()
+        .map(|holder| match &holder.wrapped_by {
+            Some(wrapper) => format!("{} (via {})", holder.t_id, wrapper),
+            None => holder.t_id.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
@@ -0,0 +1,517 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use elasticsearch::{BulkOperation, BulkParts, Elasticsearch};
+use futures::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    sync::{mpsc::UnboundedReceiver, oneshot},
+    time::Instant,
+};
+
+use crate::metrics::Metrics;
+
+// Statistics are written by a single background task so that no test ever
+// waits on the database. Documents are sent in batches through the bulk API
+// and retried with backoff. If the cluster stays down they are appended to a
+// spool file, which is replayed (in order, before anything newer) once the
+// cluster comes back. The spool survives the orchestrator restarting.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpooledDocument {
+    pub index: String,
+    pub document: Value,
+}
+
+#[derive(Debug)]
+pub enum BulkMessage {
+    Document(SpooledDocument),
+    // Send (or spool) everything received so far, then reply
+    Flush(oneshot::Sender<()>),
+}
+
+// Where batches end up. This is Elasticsearch outside of unit tests.
+pub trait BulkTarget: Send + Sync + 'static {
+    // Returns the documents that were not stored but might be if sent again.
+    // Documents that can never be stored are logged and dropped.
+    fn send(&self, batch: Vec<SpooledDocument>) -> BoxFuture<'static, Vec<SpooledDocument>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct BulkSettings {
+    pub batch_size: usize,
+    // How long a partial batch may wait for more documents
+    pub flush_interval: Duration,
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // How often to check if the cluster is back while documents are being spooled
+    pub probe_interval: Duration,
+}
+
+impl Default for BulkSettings {
+    fn default() -> Self {
+        BulkSettings {
+            batch_size: 500,
+            flush_interval: Duration::from_secs(1),
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            probe_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+pub struct BulkWriter<T: BulkTarget> {
+    input: UnboundedReceiver<BulkMessage>,
+    target: T,
+    spool: Spool,
+    settings: BulkSettings,
+    // When to next try replaying the spool
+    next_probe: Instant,
+    // Spooled and dropped documents are counted as stats sink errors
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl<T: BulkTarget> BulkWriter<T> {
+    pub fn new(
+        input: UnboundedReceiver<BulkMessage>,
+        target: T,
+        spool_path: PathBuf,
+        settings: BulkSettings,
+    ) -> Self {
+        Self {
+            input,
+            target,
+            spool: Spool::open(spool_path),
+            settings,
+            next_probe: Instant::now(),
+            metrics: None,
+        }
+    }
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+    pub async fn run(mut self) {
+        let mut batch = Vec::new();
+        let mut flushed = Vec::new();
+        let mut deadline = None;
+        loop {
+            // A partial batch waits until its deadline,
+            // and a spool until it is time to see if the cluster is back
+            let wake = match deadline {
+                Some(deadline) => Some(deadline),
+                None if !self.spool.is_empty() => Some(self.next_probe),
+                None => None,
+            };
+            tokio::select! {
+                message = self.input.recv() => match message {
+                    Some(BulkMessage::Document(document)) => {
+                        batch.push(document);
+                        deadline.get_or_insert_with(|| Instant::now() + self.settings.flush_interval);
+                        if batch.len() < self.settings.batch_size {
+                            continue;
+                        }
+                    }
+                    Some(BulkMessage::Flush(reply)) => flushed.push(reply),
+                    None => {
+                        self.deliver(std::mem::take(&mut batch)).await;
+                        break;
+                    }
+                },
+                _ = tokio::time::sleep_until(wake.unwrap_or_else(Instant::now)), if wake.is_some() => {}
+            }
+            self.deliver(std::mem::take(&mut batch)).await;
+            deadline = None;
+            for reply in flushed.drain(..) {
+                let _ = reply.send(());
+            }
+        }
+        log::info!("Statistics writer closing");
+    }
+    async fn deliver(&mut self, batch: Vec<SpooledDocument>) {
+        if !self.spool.is_empty() && Instant::now() >= self.next_probe {
+            self.replay_spool().await;
+        }
+        if batch.is_empty() {
+            return;
+        }
+        // Anything newer has to wait behind the spool to keep documents in order
+        let unsent = if self.spool.is_empty() {
+            self.send_with_retry(batch).await
+        } else {
+            batch
+        };
+        if !unsent.is_empty() {
+            if self.spool.is_empty() {
+                log::warn!(
+                    "Statistics backend is unreachable, spooling to {:?}",
+                    self.spool.path
+                );
+                self.next_probe = Instant::now() + self.settings.probe_interval;
+            }
+            count_errors(self.metrics.as_deref(), &unsent);
+            self.spool.append(&unsent);
+        }
+    }
+    async fn send_with_retry(&self, mut batch: Vec<SpooledDocument>) -> Vec<SpooledDocument> {
+        let mut backoff = self.settings.initial_backoff;
+        for attempt in 1..=self.settings.max_attempts.max(1) {
+            batch = self.target.send(batch).await;
+            if batch.is_empty() || attempt == self.settings.max_attempts {
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.settings.max_backoff);
+        }
+        batch
+    }
+    // Sends the spool one batch at a time, stopping at the first batch that fails
+    async fn replay_spool(&mut self) {
+        let mut spooled = self.spool.read_all();
+        let total = spooled.len();
+        while !spooled.is_empty() {
+            let rest = spooled.split_off(spooled.len().min(self.settings.batch_size));
+            let unsent = self.target.send(spooled).await;
+            if !unsent.is_empty() {
+                spooled = unsent;
+                spooled.extend(rest);
+                break;
+            }
+            spooled = rest;
+        }
+        self.spool.replace(&spooled);
+        if spooled.is_empty() {
+            log::info!("Statistics backend is back, replayed {} documents", total);
+        } else {
+            self.next_probe = Instant::now() + self.settings.probe_interval;
+        }
+    }
+}
+
+// Documents waiting for the cluster to come back, one json object per line.
+// Only ever touched by the writer task.
+struct Spool {
+    path: PathBuf,
+    len: usize,
+}
+
+fn count_errors(metrics: Option<&Metrics>, documents: &[SpooledDocument]) {
+    if let Some(metrics) = metrics {
+        for document in documents {
+            metrics.stats_sink_error(&document.index);
+        }
+    }
+}
+
+impl Spool {
+    fn open(path: PathBuf) -> Self {
+        let len = read_spool(&path).map(|docs| docs.len()).unwrap_or(0);
+        if len > 0 {
+            log::info!("{} statistics documents are waiting in {:?}", len, path);
+        }
+        Self { path, len }
+    }
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    fn append(&mut self, documents: &[SpooledDocument]) {
+        let result = (|| -> anyhow::Result<()> {
+            if let Some(parent) = self.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            for document in documents {
+                let mut line = serde_json::to_string(document)?;
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => self.len += documents.len(),
+            Err(err) => log::error!(
+                "Dropping {} statistics documents, unable to spool them to {:?}: {:?}",
+                documents.len(),
+                self.path,
+                err
+            ),
+        }
+    }
+    fn read_all(&self) -> Vec<SpooledDocument> {
+        read_spool(&self.path).unwrap_or_else(|err| {
+            log::error!("Unable to read the spool {:?}: {:?}", self.path, err);
+            Vec::new()
+        })
+    }
+    fn replace(&mut self, documents: &[SpooledDocument]) {
+        let result = if documents.is_empty() {
+            std::fs::remove_file(&self.path).or_else(|err| match err.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(err),
+            })
+        } else {
+            // Written next to the spool and renamed over it so a crash leaves one or the other
+            let tmp = self.path.with_extension("jsonl.tmp");
+            (|| {
+                let mut file = File::create(&tmp)?;
+                for document in documents {
+                    serde_json::to_writer(&mut file, document)?;
+                    file.write_all(b"\n")?;
+                }
+                std::fs::rename(&tmp, &self.path)
+            })()
+        };
+        if let Err(err) = result {
+            log::error!("Unable to rewrite the spool {:?}: {:?}", self.path, err);
+        }
+        self.len = documents.len();
+    }
+}
+
+fn read_spool(path: &Path) -> anyhow::Result<Vec<SpooledDocument>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("Opening {:?}", path)),
+    };
+    let mut documents = Vec::new();
+    for line in BufReader::new(file).lines() {
+        // A torn final line is only possible if we crashed mid write
+        if let Ok(document) = serde_json::from_str(&line?) {
+            documents.push(document);
+        }
+    }
+    Ok(documents)
+}
+
+pub struct ElasticsearchBulk {
+    pub client: Elasticsearch,
+    // Documents that are dropped are counted as stats sink errors
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+// Worth sending again, as opposed to e.g. a mapping error
+fn is_retryable(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+impl BulkTarget for ElasticsearchBulk {
+    fn send(&self, batch: Vec<SpooledDocument>) -> BoxFuture<'static, Vec<SpooledDocument>> {
+        let client = self.client.clone();
+        let metrics = self.metrics.clone();
+        async move {
+            let operations: Vec<BulkOperation<&Value>> = batch
+                .iter()
+                .map(|doc| BulkOperation::index(&doc.document).index(&doc.index).into())
+                .collect();
+            let response = match client.bulk(BulkParts::None).body(operations).send().await {
+                Ok(response) => response,
+                Err(err) => {
+                    log::warn!("Bulk request of {} documents failed: {}", batch.len(), err);
+                    return batch;
+                }
+            };
+            let status = response.status_code().as_u16();
+            if !response.status_code().is_success() {
+                let body = response.text().await.unwrap_or_default();
+                if is_retryable(status) {
+                    log::warn!("Bulk request was rejected with {}: {}", status, body);
+                    return batch;
+                }
+                log::error!(
+                    "Dropping {} documents, bulk request failed with {}: {}",
+                    batch.len(),
+                    status,
+                    body
+                );
+                count_errors(metrics.as_deref(), &batch);
+                return Vec::new();
+            }
+            let body: Value = match response.json().await {
+                Ok(body) => body,
+                Err(err) => {
+                    log::warn!("Unreadable bulk response: {}", err);
+                    return batch;
+                }
+            };
+            if body["errors"] != true {
+                return Vec::new();
+            }
+            let items = body["items"].as_array().cloned().unwrap_or_default();
+            batch
+                .into_iter()
+                .zip(items)
+                .filter_map(|(doc, item)| {
+                    let status = item["index"]["status"].as_u64().unwrap_or(500) as u16;
+                    if status < 300 {
+                        None
+                    } else if is_retryable(status) {
+                        Some(doc)
+                    } else {
+                        log::error!(
+                            "Dropping a document for {}: {}",
+                            doc.index,
+                            item["index"]["error"]
+                        );
+                        count_errors(metrics.as_deref(), std::slice::from_ref(&doc));
+                        None
+                    }
+                })
+                .collect()
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    };
+
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{resource_ledger::SharedLedger, running_test_map::RunningTestMap};
+
+    #[derive(Clone, Default)]
+    struct FakeCluster {
+        down: Arc<AtomicBool>,
+        batches: Arc<Mutex<Vec<Vec<SpooledDocument>>>>,
+    }
+
+    impl FakeCluster {
+        fn received(&self) -> Vec<Value> {
+            let batches = self.batches.lock().unwrap();
+            batches
+                .iter()
+                .flatten()
+                .map(|doc| doc.document.clone())
+                .collect()
+        }
+    }
+
+    impl BulkTarget for FakeCluster {
+        fn send(&self, batch: Vec<SpooledDocument>) -> BoxFuture<'static, Vec<SpooledDocument>> {
+            if self.down.load(Ordering::SeqCst) {
+                return async { batch }.boxed();
+            }
+            self.batches.lock().unwrap().push(batch);
+            async { Vec::new() }.boxed()
+        }
+    }
+
+    fn settings() -> BulkSettings {
+        BulkSettings {
+            batch_size: 2,
+            flush_interval: Duration::from_millis(10),
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            probe_interval: Duration::from_millis(5),
+        }
+    }
+
+    fn spool_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("htp-spool-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("spool.jsonl")
+    }
+
+    fn send(sender: &mpsc::UnboundedSender<BulkMessage>, n: u64) {
+        let document = SpooledDocument {
+            index: "logs".into(),
+            document: json!({ "log_line": n }),
+        };
+        sender.send(BulkMessage::Document(document)).unwrap();
+    }
+
+    async fn flush(sender: &mpsc::UnboundedSender<BulkMessage>) {
+        let (reply, flushed) = oneshot::channel();
+        sender.send(BulkMessage::Flush(reply)).unwrap();
+        flushed.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batches_documents() {
+        let cluster = FakeCluster::default();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let path = spool_path("batches");
+        tokio::spawn(BulkWriter::new(receiver, cluster.clone(), path, settings()).run());
+        for n in 0..5 {
+            send(&sender, n);
+        }
+        flush(&sender).await;
+        let sizes: Vec<usize> = cluster
+            .batches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(Vec::len)
+            .collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_spools_until_cluster_returns() {
+        let cluster = FakeCluster::default();
+        cluster.down.store(true, Ordering::SeqCst);
+        let path = spool_path("outage");
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(
+            BulkWriter::new(receiver, cluster.clone(), path.clone(), settings())
+                .with_metrics(Arc::clone(&metrics))
+                .run(),
+        );
+        for n in 0..3 {
+            send(&sender, n);
+        }
+        flush(&sender).await;
+        assert!(cluster.received().is_empty());
+        assert_eq!(read_spool(&path).unwrap().len(), 3);
+        // The outage shows up as errors even though nothing was lost yet
+        let text = metrics
+            .render(
+                &Mutex::new(RunningTestMap::default()),
+                &SharedLedger::default(),
+            )
+            .unwrap();
+        assert!(
+            text.contains("htp_stats_sink_errors_total{index=\"logs\"} 3"),
+            "{}",
+            text
+        );
+
+        // Nothing is lost if the orchestrator restarts during the outage
+        drop(sender);
+        writer.await.unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(BulkWriter::new(receiver, cluster.clone(), path.clone(), settings()).run());
+
+        cluster.down.store(false, Ordering::SeqCst);
+        send(&sender, 3);
+        flush(&sender).await;
+        let received: Vec<u64> = cluster
+            .received()
+            .iter()
+            .map(|doc| doc["log_line"].as_u64().unwrap())
+            .collect();
+        assert_eq!(received, vec![0, 1, 2, 3]);
+        assert!(!path.exists());
+    }
+}
@@ -1,9 +1,11 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::Path};
 
 pub type ApparatusMap = HashMap<String, Apparatus>;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Apparatus {
     #[serde(default = "default_exclusively_locked")]
     pub is_exclusively_locked: bool,
@@ -26,14 +28,15 @@ fn default_exclusively_locked() -> bool {
     true
 }
 
-pub fn parse(path: &PathBuf) -> Result<ApparatusMap, anyhow::Error> {
-    let json5_str = std::fs::read_to_string(path)?;
-    let apparatuses: ApparatusMap = json5::from_str(&json5_str)?;
+pub fn parse(path: &Path) -> Result<ApparatusMap, anyhow::Error> {
+    let apparatuses: ApparatusMap = super::parse_file(path)?;
     Ok(apparatuses)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+
     use super::*;
     #[test]
     fn test_parse() {
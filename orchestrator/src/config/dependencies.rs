@@ -1,24 +1,40 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::Path, time::Duration};
 
 pub type DependencyMap = HashMap<String, DependencySpecification>;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DependencySpecification {
     pub url: String,
     pub build_on: String,
     pub build_script: String,
     pub install_script: String,
+    #[serde(default)]
+    pub build_timeout_sec: Option<u64>,
+    #[serde(default)]
+    pub install_timeout_sec: Option<u64>,
 }
 
-pub fn parse(path: &PathBuf) -> Result<DependencyMap, anyhow::Error> {
-    let json5_str = std::fs::read_to_string(path)?;
-    let dependencies: DependencyMap = json5::from_str(&json5_str)?;
+impl DependencySpecification {
+    pub fn build_timeout(&self) -> Option<Duration> {
+        self.build_timeout_sec.map(Duration::from_secs)
+    }
+    pub fn install_timeout(&self) -> Option<Duration> {
+        self.install_timeout_sec.map(Duration::from_secs)
+    }
+}
+
+pub fn parse(path: &Path) -> Result<DependencyMap, anyhow::Error> {
+    let dependencies: DependencyMap = super::parse_file(path)?;
     Ok(dependencies)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+
     use super::*;
     #[test]
     fn test_parse() {
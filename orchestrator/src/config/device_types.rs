@@ -1,9 +1,13 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 pub type DeviceTypeMap = HashMap<String, DeviceType>;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct DeviceType {
     pub architecture: String,
     pub os: String,
@@ -11,23 +15,26 @@ pub struct DeviceType {
     pub classification: DeviceClassification,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+// serde cannot reject unknown keys on DeviceType because it flattens this,
+// so they are rejected here instead. Real has braces so that it rejects them too.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 #[serde(tag = "classification")]
 #[serde(rename_all = "snake_case")]
 pub enum DeviceClassification {
-    Real,
+    Real {},
     Docker(DockerSpec),
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DockerSpec {
     pub image: String,
     pub htp_root: PathBuf,
 }
 
-pub fn parse(path: &PathBuf) -> Result<DeviceTypeMap, anyhow::Error> {
-    let json5_str = std::fs::read_to_string(path)?;
-    let device_types: DeviceTypeMap = json5::from_str(&json5_str)?;
+pub fn parse(path: &Path) -> Result<DeviceTypeMap, anyhow::Error> {
+    let device_types: DeviceTypeMap = super::parse_file(path)?;
     Ok(device_types)
 }
 
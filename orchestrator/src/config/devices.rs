@@ -1,9 +1,11 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::Path};
 
 pub type DeviceMap = HashMap<String, Device>;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Device {
     #[serde(rename = "type")]
     pub device_type: String,
@@ -11,14 +13,15 @@ pub struct Device {
     pub connected_apparatuses: Vec<String>,
 }
 
-pub fn parse(path: &PathBuf) -> Result<DeviceMap, anyhow::Error> {
-    let json5_str = std::fs::read_to_string(path)?;
-    let devices: DeviceMap = json5::from_str(&json5_str)?;
+pub fn parse(path: &Path) -> Result<DeviceMap, anyhow::Error> {
+    let devices: DeviceMap = super::parse_file(path)?;
     Ok(devices)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+
     use super::*;
 
     #[test]
@@ -1,6 +1,7 @@
-use std::path::PathBuf;
+use std::path::Path;
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use serde::de::DeserializeOwned;
 
 use self::{
     apparatuses::ApparatusMap, dependencies::DependencyMap, device_types::DeviceTypeMap,
@@ -12,7 +13,10 @@ pub mod dependencies;
 pub mod device_types;
 pub mod devices;
 pub mod orchestrator_config;
+pub mod schema;
+pub mod snapshots;
 pub mod tests;
+pub mod validate;
 
 #[derive(Debug, Default)]
 pub struct Config {
@@ -24,7 +28,7 @@ pub struct Config {
 }
 
 impl Config {
-    pub fn new(base_path: &PathBuf) -> anyhow::Result<Config> {
+    pub fn new(base_path: &Path) -> anyhow::Result<Config> {
         let result = Config {
             apparatuses: apparatuses::parse(&base_path.join("apparatuses.json5"))
                 .context("Failed to read apparatuses.json5")?,
@@ -37,12 +41,53 @@ impl Config {
             tests: tests::parse(&base_path.join("tests.json5"))
                 .context("Failed to read tests.json5")?,
         };
+        result.validate(base_path)?;
         Ok(result)
     }
 }
 
+// Reads any of the json5 config files. Errors carry the file, line and column,
+// and suggest the closest valid key when an unknown one is used.
+pub fn parse_file<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let json5_str =
+        std::fs::read_to_string(path).with_context(|| format!("Unable to read {:?}", path))?;
+    parse_str(&json5_str).map_err(|err| anyhow!("{}:{}", path.display(), err))
+}
+
+// The error starts with "line:column: " when json5 knows where it happened
+fn parse_str<T: DeserializeOwned>(json5_str: &str) -> Result<T, String> {
+    json5::from_str(json5_str).map_err(|err| {
+        let json5::Error::Message { msg, location } = err;
+        let msg = match suggestion(&msg) {
+            Some(closest) => format!("{}, did you mean `{}`?", msg, closest),
+            None => msg,
+        };
+        match location {
+            Some(location) => format!("{}:{}: {}", location.line, location.column, msg),
+            None => format!(" {}", msg),
+        }
+    })
+}
+
+// Serde reports unknown keys and enum variants as
+// "unknown field `x`, expected one of `a`, `b`"
+fn suggestion(msg: &str) -> Option<&str> {
+    if !msg.starts_with("unknown field") && !msg.starts_with("unknown variant") {
+        return None;
+    }
+    let mut quoted = msg.split('`').skip(1).step_by(2);
+    let unknown = quoted.next()?;
+    quoted
+        .map(|candidate| (strsim::jaro_winkler(unknown, candidate), candidate))
+        .filter(|(similarity, _)| *similarity >= 0.7)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, candidate)| candidate)
+}
+
 #[cfg(test)]
 mod test_mod {
+    use std::path::PathBuf;
+
     use super::*;
     #[test]
     fn test_parse() -> anyhow::Result<()> {
@@ -50,4 +95,99 @@ mod test_mod {
         let _ = Config::new(&base_path)?;
         Ok(())
     }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse_str::<tests::TestMap>(
+            "{\n  general: [{\n    name: 'a',\n    sdk_test_scrpt: 'x',\n  }]\n}",
+        )
+        .unwrap_err();
+        assert!(
+            err.starts_with("4:5: unknown field `sdk_test_scrpt`"),
+            "{}",
+            err
+        );
+        let err = parse_str::<devices::DeviceMap>("{ pi: { tpye: 'rpi' } }").unwrap_err();
+        assert!(err.ends_with("did you mean `type`?"), "{}", err);
+        // Nothing close enough to suggest
+        let err = parse_str::<devices::DeviceMap>("{ pi: { colour: 'red' } }").unwrap_err();
+        assert!(!err.contains("did you mean"), "{}", err);
+        let err = parse_str::<devices::DeviceMap>("{ pi: { type: 'rpi', } ").unwrap_err();
+        assert!(err.starts_with("1:22: "), "{}", err);
+
+        let err = parse_file::<devices::DeviceMap>(Path::new("../example_config/tests.json5"))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("../example_config/tests.json5:3:9: "),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let base_path = PathBuf::from("../example_config");
+        let mut config = Config::new(&base_path).unwrap();
+        config
+            .devices
+            .get_mut("testing-rpi-4b-1.local")
+            .unwrap()
+            .device_type = "rpi4".into();
+        for (name, wrapped) in [("a", "b"), ("b", "a")] {
+            config.apparatuses.insert(
+                name.into(),
+                apparatuses::Apparatus {
+                    wrapped_apparatuses: vec![wrapped.into()],
+                    ..Default::default()
+                },
+            );
+        }
+        config
+            .apparatuses
+            .get_mut("software-only")
+            .unwrap()
+            .wrapped_apparatuses
+            .push("missing".into());
+        config
+            .dependencies
+            .get_mut("viam_server_appimage")
+            .unwrap()
+            .build_on = "*".into();
+        let test = &mut config.tests.get_mut("general").unwrap().0[0];
+        test.apparatus = "software_only".into();
+        test.excluded_device_types.push("rpi-4b-2gb".into());
+        test.dependencies
+            .insert("viam_python_sdk".into(), "HEAD".into());
+        test.robot_config = "./robot_cfgs/missing.json".into();
+        test.on_device_test_script = None;
+
+        let problems = config.validate(&base_path).unwrap_err().0;
+        assert_eq!(
+            problems,
+            vec![
+                "Device testing-rpi-4b-1.local has unknown type rpi4",
+                "Apparatus software-only wraps unknown apparatus missing",
+                "Apparatus a wraps itself through a -> b -> a",
+                "Dependency viam_server_appimage is built on unknown device type *",
+                "Test general/startup needs unknown apparatus software_only",
+                "Test general/startup excludes unknown device type rpi-4b-2gb",
+                "Test general/startup depends on unknown dependency viam_python_sdk",
+                "Test general/startup uses robot config ./robot_cfgs/missing.json which is not in \"../example_config\"",
+                "Test general/startup has no test script, set on_device_test_script",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_snapshots() {
+        let base_path = PathBuf::from("../example_config");
+        let mut config = Config::new(&base_path).unwrap();
+        config.devices.remove("testing-rpi-4b-1.local");
+        let problems = config.validate(&base_path).unwrap_err().0;
+        assert_eq!(
+            problems,
+            vec!["Snapshots are saved for unknown device testing-rpi-4b-1.local"]
+        );
+    }
 }
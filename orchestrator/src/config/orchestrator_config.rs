@@ -1,18 +1,171 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct OrchestratorConfig {
     pub htp_folder_root: PathBuf,
     pub persist_test_runs: bool,
     pub host_addr: String,
     pub loki_addr: String,
     pub elastic_addr: String,
+    /// Where Prometheus scrapes /metrics from. "" disables the endpoint.
+    #[serde(default = "default_metrics_addr")]
+    pub metrics_addr: String,
+    /// Where the API for submitting and cancelling tests listens. "" disables it.
+    #[serde(default = "default_api_addr")]
+    pub api_addr: String,
+    /// Where test statistics and logs are recorded
+    #[serde(default)]
+    pub stats_backend: StatsBackend,
+    #[serde(default)]
+    pub stage_concurrency: StageConcurrency,
+    /// How long in-flight tests get to clean up after a shutdown is requested
+    #[serde(default = "default_shutdown_drain_timeout_sec")]
+    pub shutdown_drain_timeout_sec: u64,
+    /// Waiting tests gain one priority level every this many seconds
+    /// so that low priority tests are never starved. 0 disables aging.
+    #[serde(default = "default_priority_aging_sec")]
+    pub priority_aging_sec: u64,
+    /// Locks on devices and apparatuses are reclaimed if the test holding them
+    /// stops renewing them for this long, e.g. because its worker crashed
+    #[serde(default = "default_lease_ttl_sec")]
+    pub lease_ttl_sec: u64,
+    /// How often a running test's container cpu and memory use is recorded.
+    /// Docker only reports this about once a second. 0 disables sampling.
+    #[serde(default = "default_utilization_sample_interval_sec")]
+    pub utilization_sample_interval_sec: u64,
 }
 
-pub fn parse(path: &PathBuf) -> anyhow::Result<OrchestratorConfig> {
-    let json5_str = std::fs::read_to_string(path)?;
-    let dependencies: OrchestratorConfig = json5::from_str(&json5_str)?;
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StatsBackend {
+    /// The cluster at elastic_addr
+    #[default]
+    Elasticsearch,
+    /// One newline-delimited json file per index in this folder.
+    /// A relative folder is inside htp_folder_root.
+    JsonLines { folder: PathBuf },
+    /// Discarded when the orchestrator exits
+    Memory,
+}
+
+impl OrchestratorConfig {
+    // Relative paths in the config are kept inside htp_folder_root,
+    // so overriding it moves everything the orchestrator writes
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        self.htp_folder_root.join(path)
+    }
+}
+
+fn default_metrics_addr() -> String {
+    "0.0.0.0:9184".into()
+}
+
+fn default_api_addr() -> String {
+    "127.0.0.1:9185".into()
+}
+
+fn default_shutdown_drain_timeout_sec() -> u64 {
+    60
+}
+
+fn default_priority_aging_sec() -> u64 {
+    600
+}
+
+fn default_lease_ttl_sec() -> u64 {
+    300
+}
+
+fn default_utilization_sample_interval_sec() -> u64 {
+    5
+}
+
+/// How many tests each stage is allowed to work on at the same time.
+/// A value of 0 is treated as 1.
+/// There is no setting for aquisition because the Aquirer
+/// hands out resources one test at a time.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct StageConcurrency {
+    pub validation: usize,
+    pub preperation: usize,
+    pub running: usize,
+    pub termination: usize,
+}
+
+impl Default for StageConcurrency {
+    fn default() -> Self {
+        StageConcurrency {
+            validation: 4,
+            preperation: 2,
+            running: 2,
+            termination: 4,
+        }
+    }
+}
+
+// Settings that can be given on the command line or in HTP_* environment
+// variables, which take precedence over orchestrator.json5 in that order
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ConfigOverrides {
+    /// Where test runs, dependencies and the journal are kept
+    #[arg(long, env = "HTP_FOLDER_ROOT")]
+    pub htp_folder_root: Option<PathBuf>,
+    /// How this host is labelled in Loki
+    #[arg(long, env = "HTP_HOST_ADDR")]
+    pub host_addr: Option<String>,
+    /// "" disables shipping logs to Loki
+    #[arg(long, env = "HTP_LOKI_ADDR")]
+    pub loki_addr: Option<String>,
+    /// Used when stats_backend is elasticsearch
+    #[arg(long, env = "HTP_ELASTIC_ADDR")]
+    pub elastic_addr: Option<String>,
+    /// "" disables the Prometheus endpoint
+    #[arg(long, env = "HTP_METRICS_ADDR")]
+    pub metrics_addr: Option<String>,
+    /// "" disables the API
+    #[arg(long, env = "HTP_API_ADDR")]
+    pub api_addr: Option<String>,
+}
+
+impl ConfigOverrides {
+    pub fn apply(self, config: &mut OrchestratorConfig) {
+        let Self {
+            htp_folder_root,
+            host_addr,
+            loki_addr,
+            elastic_addr,
+            metrics_addr,
+            api_addr,
+        } = self;
+        if let Some(htp_folder_root) = htp_folder_root {
+            config.htp_folder_root = htp_folder_root;
+        }
+        if let Some(host_addr) = host_addr {
+            config.host_addr = host_addr;
+        }
+        if let Some(loki_addr) = loki_addr {
+            config.loki_addr = loki_addr;
+        }
+        if let Some(elastic_addr) = elastic_addr {
+            config.elastic_addr = elastic_addr;
+        }
+        if let Some(metrics_addr) = metrics_addr {
+            config.metrics_addr = metrics_addr;
+        }
+        if let Some(api_addr) = api_addr {
+            config.api_addr = api_addr;
+        }
+    }
+}
+
+pub fn parse(path: &Path) -> anyhow::Result<OrchestratorConfig> {
+    let dependencies: OrchestratorConfig = super::parse_file(path)?;
     Ok(dependencies)
 }
 
@@ -21,7 +174,60 @@ mod tests {
     use super::*;
     #[test]
     fn test_parse() {
+        let path = PathBuf::from("../example_config/orchestrator.json5");
+        let _orchestrator = parse(&path).unwrap();
+    }
+    #[test]
+    fn test_parse_stage_concurrency() {
+        let path = PathBuf::from("../example_config/orchestrator.json5");
+        let orchestrator = parse(&path).unwrap();
+        assert_eq!(orchestrator.stage_concurrency.running, 4);
+        // unspecified stages fall back to their defaults
+        assert_eq!(orchestrator.stage_concurrency.validation, 4);
+        assert_eq!(orchestrator.shutdown_drain_timeout_sec, 30);
+    }
+    #[test]
+    fn test_overrides() {
+        #[derive(clap::Parser)]
+        struct Args {
+            #[command(flatten)]
+            overrides: ConfigOverrides,
+        }
+        let path = PathBuf::from("../example_config/orchestrator.json5");
+        let mut orchestrator = parse(&path).unwrap();
+        let args = <Args as clap::Parser>::try_parse_from([
+            "orchestrator",
+            "--htp-folder-root",
+            "/tmp/htp",
+            "--api-addr",
+            "",
+        ])
+        .unwrap();
+        args.overrides.apply(&mut orchestrator);
+        assert_eq!(orchestrator.htp_folder_root, PathBuf::from("/tmp/htp"));
+        assert_eq!(
+            orchestrator.resolve(Path::new("stats")),
+            PathBuf::from("/tmp/htp/stats")
+        );
+        assert_eq!(
+            orchestrator.resolve(Path::new("/var/htp/stats")),
+            PathBuf::from("/var/htp/stats")
+        );
+        assert_eq!(orchestrator.api_addr, "");
+        // Anything not overridden comes from the file
+        assert_eq!(orchestrator.elastic_addr, "elastic.localhost");
+    }
+    #[test]
+    fn test_parse_stats_backend() {
         let path = PathBuf::from("../example_config/orchestrator.json5");
         let orchestrator = parse(&path).unwrap();
+        assert_eq!(
+            orchestrator.stats_backend,
+            StatsBackend::JsonLines {
+                folder: "stats".into()
+            }
+        );
+        let backend: StatsBackend = json5::from_str("{ type: 'elasticsearch' }").unwrap();
+        assert_eq!(backend, StatsBackend::Elasticsearch);
     }
 }
@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use anyhow::Context;
+use schemars::{
+    gen::SchemaSettings,
+    schema::{RootSchema, Schema, SchemaObject},
+    JsonSchema,
+};
+
+use super::{
+    apparatuses::ApparatusMap, dependencies::DependencyMap, device_types::DeviceTypeMap,
+    devices::DeviceMap, orchestrator_config::OrchestratorConfig, tests::TestMap,
+};
+
+// JSON Schemas for the hand edited config files, so that editors and CI
+// can check them. Each is named after the file it describes.
+pub fn schemas() -> Vec<(&'static str, RootSchema)> {
+    vec![
+        ("apparatuses.schema.json", schema_for::<ApparatusMap>()),
+        ("dependencies.schema.json", schema_for::<DependencyMap>()),
+        ("devices.schema.json", schema_for::<DeviceMap>()),
+        ("device_types.schema.json", schema_for::<DeviceTypeMap>()),
+        (
+            "orchestrator.schema.json",
+            schema_for::<OrchestratorConfig>(),
+        ),
+        ("tests.schema.json", schema_for::<TestMap>()),
+    ]
+}
+
+fn schema_for<T: JsonSchema>() -> RootSchema {
+    let mut root = SchemaSettings::draft07()
+        .into_generator()
+        .into_root_schema_for::<T>();
+    inline_flattened_fields(&mut root.schema);
+    for definition in root.definitions.values_mut() {
+        if let Schema::Object(definition) = definition {
+            inline_flattened_fields(definition);
+        }
+    }
+    root
+}
+
+// A struct that flattens a tagged enum (DeviceType) gets its own fields next to
+// the enum's variants, which reject them as unknown. Copying the fields into
+// every variant keeps the schema exactly as strict as the deserializer.
+fn inline_flattened_fields(schema: &mut SchemaObject) {
+    let Some(variants) = schema
+        .subschemas
+        .as_mut()
+        .and_then(|subschemas| subschemas.one_of.as_mut())
+    else {
+        return;
+    };
+    let Some(fields) = schema.object.take() else {
+        return;
+    };
+    for variant in variants {
+        if let Schema::Object(variant) = variant {
+            let object = variant.object();
+            object.properties.extend(fields.properties.clone());
+            object.required.extend(fields.required.clone());
+        }
+    }
+}
+
+// Pretty printed, so the files diff well when a config type changes
+pub fn render(schema: &RootSchema) -> anyhow::Result<String> {
+    Ok(serde_json::to_string_pretty(schema)? + "\n")
+}
+
+pub fn write(folder: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(folder).with_context(|| format!("Creating {:?}", folder))?;
+    for (file_name, schema) in schemas() {
+        let path = folder.join(file_name);
+        std::fs::write(&path, render(&schema)?).with_context(|| format!("Writing {:?}", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    // CI fails if a config type changes without the schemas being regenerated
+    #[test]
+    fn test_schemas_up_to_date() {
+        let folder = PathBuf::from("../docs/schemas");
+        for (file_name, schema) in schemas() {
+            let committed = std::fs::read_to_string(folder.join(file_name)).unwrap_or_default();
+            assert!(
+                committed == render(&schema).unwrap(),
+                "docs/schemas/{} is out of date, run `orchestrator --write-schemas ../docs/schemas`",
+                file_name
+            );
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+// internal/snapshots.json5, the disk images saved for each device.
+// Only ever edited by the orchestrator, see internal/README.md.
+pub type SnapshotMap = BTreeMap<String, DeviceSnapshots>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSnapshots {
+    pub snapshots: Vec<Snapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot {
+    pub path: String,
+}
+
+const HEADER: &str = "// WARNING: read README.md before editing these files\n";
+
+pub fn parse(path: &Path) -> Result<SnapshotMap, anyhow::Error> {
+    let snapshots: SnapshotMap = super::parse_file(path)?;
+    Ok(snapshots)
+}
+
+// Json is valid json5, so the file stays readable by parse
+pub fn write(path: &Path, snapshots: &SnapshotMap) -> Result<(), anyhow::Error> {
+    let body = serde_json::to_string_pretty(snapshots)?;
+    std::fs::write(path, format!("{}{}\n", HEADER, body))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let path = PathBuf::from("../example_config/internal/snapshots.json5");
+        let snapshots = parse(&path).unwrap();
+        assert_eq!(
+            snapshots["testing-rpi-4b-1.local"].snapshots[0].path,
+            "test"
+        );
+    }
+}
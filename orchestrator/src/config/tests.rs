@@ -1,14 +1,15 @@
 use anyhow::anyhow;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::Path, time::Duration};
 
 // Test type, test name
 pub type TestSpecificationID = (String, String);
 
 pub type TestMap = HashMap<String, TestGroup>;
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct TestGroup(Vec<TestSpecification>);
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct TestGroup(pub Vec<TestSpecification>);
 
 impl TestGroup {
     pub fn validate(&self) -> anyhow::Result<()> {
@@ -24,16 +25,12 @@ impl TestGroup {
         Ok(())
     }
     pub fn get_test(&self, test_name: &str) -> Option<&TestSpecification> {
-        for test in &self.0 {
-            if test.name == test_name {
-                return Some(&test);
-            }
-        }
-        return None;
+        self.0.iter().find(|test| test.name == test_name)
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct TestSpecification {
     pub name: String,
     pub dependencies: HashMap<String, String>,
@@ -44,11 +41,20 @@ pub struct TestSpecification {
     pub remote_test_script: Option<String>,
     #[serde(default)]
     pub on_device_test_script: Option<String>,
+    /// The test is killed if installing dependencies and running
+    /// the test script takes longer than this
+    #[serde(default)]
+    pub max_duration_sec: Option<u64>,
 }
 
-pub fn parse(path: &PathBuf) -> Result<TestMap, anyhow::Error> {
-    let json5_str = std::fs::read_to_string(path)?;
-    let tests: TestMap = json5::from_str(&json5_str)?;
+impl TestSpecification {
+    pub fn max_duration(&self) -> Option<Duration> {
+        self.max_duration_sec.map(Duration::from_secs)
+    }
+}
+
+pub fn parse(path: &Path) -> Result<TestMap, anyhow::Error> {
+    let tests: TestMap = super::parse_file(path)?;
     for test_group in tests.values() {
         test_group.validate()?;
     }
@@ -56,7 +62,10 @@ pub fn parse(path: &PathBuf) -> Result<TestMap, anyhow::Error> {
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
+    use std::path::PathBuf;
+
     use super::*;
     #[test]
     fn test_parse_tests() {
@@ -0,0 +1,180 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use super::{snapshots, Config};
+
+// Everything wrong with the references between the config files
+#[derive(thiserror::Error, Debug)]
+pub struct ConfigProblems(pub Vec<String>);
+
+impl fmt::Display for ConfigProblems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) in the config:", self.0.len())?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    InProgress,
+    Done,
+}
+
+impl Config {
+    // Checks that every name one file uses is defined in another, including
+    // internal/snapshots.json5, and that robot configs exist relative to `base_path`.
+    // Every problem is reported, not just the first.
+    pub fn validate(&self, base_path: &Path) -> Result<(), ConfigProblems> {
+        let mut problems = Vec::new();
+
+        for (name, device) in sorted(&self.devices) {
+            if !self.device_types.contains_key(&device.device_type) {
+                problems.push(format!(
+                    "Device {} has unknown type {}",
+                    name, device.device_type
+                ));
+            }
+            for apparatus in &device.connected_apparatuses {
+                if !self.apparatuses.contains_key(apparatus) {
+                    problems.push(format!(
+                        "Device {} is connected to unknown apparatus {}",
+                        name, apparatus
+                    ));
+                }
+            }
+        }
+
+        for (name, apparatus) in sorted(&self.apparatuses) {
+            for wrapped in &apparatus.wrapped_apparatuses {
+                if !self.apparatuses.contains_key(wrapped) {
+                    problems.push(format!(
+                        "Apparatus {} wraps unknown apparatus {}",
+                        name, wrapped
+                    ));
+                }
+            }
+        }
+        let mut visits = HashMap::new();
+        for (name, _) in sorted(&self.apparatuses) {
+            self.find_wrap_cycles(name, &mut Vec::new(), &mut visits, &mut problems);
+        }
+
+        for (name, dependency) in sorted(&self.dependencies) {
+            if !self.device_types.contains_key(&dependency.build_on) {
+                problems.push(format!(
+                    "Dependency {} is built on unknown device type {}",
+                    name, dependency.build_on
+                ));
+            }
+        }
+
+        for (group_name, group) in sorted(&self.tests) {
+            for test in &group.0 {
+                let test_name = format!("{}/{}", group_name, test.name);
+                if !self.apparatuses.contains_key(&test.apparatus) {
+                    problems.push(format!(
+                        "Test {} needs unknown apparatus {}",
+                        test_name, test.apparatus
+                    ));
+                }
+                for device_type in &test.excluded_device_types {
+                    if !self.device_types.contains_key(device_type) {
+                        problems.push(format!(
+                            "Test {} excludes unknown device type {}",
+                            test_name, device_type
+                        ));
+                    }
+                }
+                let mut dependencies: Vec<&String> = test.dependencies.keys().collect();
+                dependencies.sort();
+                for dependency in dependencies {
+                    if !self.dependencies.contains_key(dependency) {
+                        problems.push(format!(
+                            "Test {} depends on unknown dependency {}",
+                            test_name, dependency
+                        ));
+                    }
+                }
+                if !base_path.join(&test.robot_config).is_file() {
+                    problems.push(format!(
+                        "Test {} uses robot config {} which is not in {:?}",
+                        test_name, test.robot_config, base_path
+                    ));
+                }
+                // remote_test_script is not run yet, so it does not count
+                if test.on_device_test_script.is_none() {
+                    problems.push(format!(
+                        "Test {} has no test script, set on_device_test_script",
+                        test_name
+                    ));
+                }
+            }
+        }
+
+        // Written by the inventory API, which only accepts known devices,
+        // but devices can be renamed or removed after their snapshots were saved
+        let snapshots_path = base_path.join("internal").join("snapshots.json5");
+        if snapshots_path.exists() {
+            match snapshots::parse(&snapshots_path) {
+                Ok(snapshots) => {
+                    for device in snapshots.keys() {
+                        if !self.devices.contains_key(device) {
+                            problems
+                                .push(format!("Snapshots are saved for unknown device {}", device));
+                        }
+                    }
+                }
+                Err(err) => problems.push(format!("{:#}", err)),
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigProblems(problems))
+        }
+    }
+    // Depth first, so each cycle is reported once from where it was entered
+    fn find_wrap_cycles<'a>(
+        &'a self,
+        apparatus: &'a str,
+        path: &mut Vec<&'a str>,
+        visits: &mut HashMap<&'a str, Visit>,
+        problems: &mut Vec<String>,
+    ) {
+        match visits.get(apparatus) {
+            Some(Visit::Done) => return,
+            Some(Visit::InProgress) => {
+                let start = path.iter().position(|a| *a == apparatus).unwrap_or(0);
+                let mut cycle = path[start..].to_vec();
+                cycle.push(apparatus);
+                problems.push(format!(
+                    "Apparatus {} wraps itself through {}",
+                    apparatus,
+                    cycle.join(" -> ")
+                ));
+                return;
+            }
+            None => {}
+        }
+        let Some(meta) = self.apparatuses.get(apparatus) else {
+            return;
+        };
+        visits.insert(apparatus, Visit::InProgress);
+        path.push(apparatus);
+        for wrapped in &meta.wrapped_apparatuses {
+            self.find_wrap_cycles(wrapped, path, visits, problems);
+        }
+        path.pop();
+        visits.insert(apparatus, Visit::Done);
+    }
+}
+
+// HashMaps iterate in a random order, which would shuffle the problems
+fn sorted<V>(map: &HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
@@ -1,15 +1,6 @@
 use super::Environment;
 
-use bollard::image::BuildImageOptions;
-use bollard::Docker;
-use futures_util::stream::StreamExt;
-use hyper::body::Body;
-
-use tokio::fs::File;
-use tokio_util::codec::{BytesCodec, FramedRead};
-
-use std::env::args;
-use tokio::time::{sleep, Duration};
+#[derive(Default)]
 pub struct DockerEnv {}
 impl DockerEnv {
     pub fn new() -> Self {
@@ -20,7 +11,7 @@ impl Environment for DockerEnv {
     fn setup(&mut self) {
         todo!()
     }
-    fn run_script(&mut self, cmd: &str) {
+    fn run_script(&mut self, _cmd: &str) {
         todo!()
     }
     fn cleanup(&mut self) {
@@ -32,6 +23,14 @@ impl Environment for DockerEnv {
 mod tests {
     use std::collections::HashMap;
 
+    use bollard::image::BuildImageOptions;
+    use bollard::Docker;
+    use futures_util::stream::StreamExt;
+    use hyper::body::Body;
+    use tokio::fs::File;
+    use tokio::time::{sleep, Duration};
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
     use super::*;
 
     #[tokio::test]
@@ -69,7 +68,7 @@ mod tests {
         while let Some(msg) = image_build_stream.next().await {
             println!("Message: {:?}", msg);
         }
-        let env = DockerEnv::new();
+        let _env = DockerEnv::new();
     }
     #[tokio::test]
     async fn supercre() {
@@ -104,8 +103,6 @@ mod tests {
             labels: build_image_labels,
             networkmode: "host",
             platform: "linux/x86_64",
-            #[cfg(feature = "buildkit")]
-            session: None,
             version: bollard::image::BuilderVersion::BuilderV1,
         };
 
@@ -1,24 +1,42 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
-use bollard::container::{Config, RemoveContainerOptions};
+use anyhow::anyhow;
+use bollard::container::{
+    CPUStats, Config, ListContainersOptions, LogOutput, MemoryStats, MemoryStatsStats,
+    RemoveContainerOptions, StatsOptions,
+};
 use bollard::Docker;
 
 use bollard::exec::{CreateExecOptions, StartExecResults};
 use bollard::image::CreateImageOptions;
 use futures_util::stream::StreamExt;
 use futures_util::TryStreamExt;
+use tokio_util::sync::CancellationToken;
 
-use crate::config::device_types::DockerSpec;
+use super::{Cancelled, ExecOutput, LineSplitter};
+use crate::{
+    config::device_types::DockerSpec,
+    statistics::{DbWrapper, UtilizationSummary},
+};
+
+// Every container the orchestrator creates is labelled with the test it belongs to
+// so that containers orphaned by a crash can be found again
+pub const TEST_ID_LABEL: &str = "htp.test_id";
 
 pub struct DockerEnvironment {
     container_id: String,
+    cancel: CancellationToken,
 }
 impl DockerEnvironment {
-    pub async fn new(spec: &DockerSpec, container_config: Config<String>) -> anyhow::Result<Self> {
+    pub async fn new(
+        spec: &DockerSpec,
+        container_config: Config<String>,
+        cancel: CancellationToken,
+    ) -> anyhow::Result<Self> {
         let docker = Docker::connect_with_socket_defaults()?;
         log::info!("Creating docker image");
 
-        docker
+        let pull = docker
             .create_image(
                 Some(CreateImageOptions {
                     from_image: spec.image.clone(),
@@ -27,8 +45,11 @@ impl DockerEnvironment {
                 None,
                 None,
             )
-            .try_collect::<Vec<_>>()
-            .await?;
+            .try_collect::<Vec<_>>();
+        tokio::select! {
+            _ = cancel.cancelled() => return Err(Cancelled.into()),
+            pulled = pull => { pulled?; }
+        }
 
         log::info!("Creating docker container");
 
@@ -39,35 +60,180 @@ impl DockerEnvironment {
             .id;
         log::info!("Starting docker container");
         docker.start_container::<String>(&id, None).await?;
-        Ok(Self { container_id: id })
+        Ok(Self {
+            container_id: id,
+            cancel,
+        })
     }
     // This does not have to be mutable but I am using the borrow checker to ensure
     // this isn't concurrently modified
-    pub async fn exec_simple(&mut self, command: &str) -> anyhow::Result<()> {
-        self.exec(CreateExecOptions {
-            attach_stdout: Some(true),
-            attach_stderr: Some(true),
-            cmd: Some(vec!["/bin/bash".into(), "-c".into(), command.into()]),
-            ..Default::default()
-        })
+    pub async fn exec_simple(
+        &mut self,
+        command: &str,
+        logs: &DbWrapper,
+    ) -> anyhow::Result<ExecOutput> {
+        self.exec(
+            CreateExecOptions {
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                cmd: Some(vec!["/bin/bash".into(), "-c".into(), command.into()]),
+                ..Default::default()
+            },
+            logs,
+        )
         .await
     }
-    pub async fn exec(&mut self, mut options: CreateExecOptions<String>) -> anyhow::Result<()> {
+    // Runs the command to completion and returns its exit code and output.
+    // A non-zero exit code is not an error here; see ExecOutput::check.
+    // Every line of output is also sent to `logs` as it arrives.
+    //
+    // Returns Err(Cancelled) if the cancellation token fires before the command finishes.
+    // The command is left running; shutdown() will kill it along with the container.
+    pub async fn exec(
+        &mut self,
+        mut options: CreateExecOptions<String>,
+        logs: &DbWrapper,
+    ) -> anyhow::Result<ExecOutput> {
         options.attach_stdout = Some(true);
         options.attach_stderr = Some(true);
         log::info!("Executing {:?} in docker container", options.cmd);
         let docker = Docker::connect_with_socket_defaults()?;
         let exec = docker.create_exec(&self.container_id, options).await?.id;
+        let mut collected = ExecOutput::default();
+        let mut stdout_lines = LineSplitter::default();
+        let mut stderr_lines = LineSplitter::default();
         if let StartExecResults::Attached { mut output, .. } =
             docker.start_exec(&exec, None).await?
         {
-            while let Some(Ok(msg)) = output.next().await {
-                print!("{}", msg);
+            loop {
+                let msg = tokio::select! {
+                    _ = self.cancel.cancelled() => return Err(Cancelled.into()),
+                    msg = output.next() => msg,
+                };
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(err)) => return Err(err.into()),
+                    None => break,
+                };
+                // The exec stream does not carry timestamps,
+                // so lines are stamped with when their chunk arrived
+                let received = chrono::offset::Utc::now();
+                let (is_stderr, message) = match msg {
+                    LogOutput::StdErr { message } => (true, message),
+                    other => (false, other.into_bytes()),
+                };
+                let (text, lines) = if is_stderr {
+                    (&mut collected.stderr, &mut stderr_lines)
+                } else {
+                    (&mut collected.stdout, &mut stdout_lines)
+                };
+                text.push_str(&String::from_utf8_lossy(&message));
+                for line in lines.push(&message) {
+                    logs.log_at(is_stderr, line, received).await;
+                }
             }
         } else {
             unreachable!();
         }
-        Ok(())
+        let received = chrono::offset::Utc::now();
+        if let Some(line) = stdout_lines.finish() {
+            logs.log_at(false, line, received).await;
+        }
+        if let Some(line) = stderr_lines.finish() {
+            logs.log_at(true, line, received).await;
+        }
+        collected.exit_code = docker
+            .inspect_exec(&exec)
+            .await?
+            .exit_code
+            .ok_or_else(|| anyhow!("Exec {} finished without an exit code", exec))?;
+        Ok(collected)
+    }
+    pub fn container_id(&self) -> &str {
+        &self.container_id
+    }
+    // Records the container's cpu and memory use every `interval` into `logs` and `summary`.
+    // This never returns, it is meant to be dropped once the test is done.
+    pub async fn sample_utilization(
+        container_id: String,
+        interval: Duration,
+        logs: &DbWrapper,
+        summary: &mut UtilizationSummary,
+    ) {
+        match Docker::connect_with_socket_defaults() {
+            Ok(docker) => {
+                let options = StatsOptions {
+                    stream: true,
+                    one_shot: false,
+                };
+                let mut stats = docker.stats(&container_id, Some(options));
+                let mut next_sample = tokio::time::Instant::now();
+                while let Some(stat) = stats.next().await {
+                    let stat = match stat {
+                        Ok(stat) => stat,
+                        Err(err) => {
+                            log::warn!("Stopped sampling container {}: {}", container_id, err);
+                            break;
+                        }
+                    };
+                    if tokio::time::Instant::now() < next_sample {
+                        continue;
+                    }
+                    next_sample = tokio::time::Instant::now() + interval;
+                    let (Some(cpu_load), Some(ram_usage)) = (
+                        cpu_load(&stat.cpu_stats, &stat.precpu_stats),
+                        ram_usage_bytes(&stat.memory_stats),
+                    ) else {
+                        continue;
+                    };
+                    let ram_usage_megabytes = (ram_usage / (1024 * 1024)) as u32;
+                    summary.record(cpu_load, ram_usage_megabytes);
+                    logs.utilization(cpu_load, ram_usage_megabytes).await;
+                }
+            }
+            Err(err) => log::warn!("Unable to sample container {}: {}", container_id, err),
+        }
+        std::future::pending().await
+    }
+    pub fn test_labels(test_id: &str) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        labels.insert(TEST_ID_LABEL.into(), test_id.into());
+        labels
+    }
+    // Force-removes every container (running or not) that was created for test_id.
+    // Returns how many were removed.
+    pub async fn remove_test_containers(test_id: &str) -> anyhow::Result<usize> {
+        let docker = Docker::connect_with_socket_defaults()?;
+        let mut filters = HashMap::new();
+        filters.insert(
+            "label".to_string(),
+            vec![format!("{}={}", TEST_ID_LABEL, test_id)],
+        );
+        let containers = docker
+            .list_containers(Some(ListContainersOptions {
+                all: true,
+                filters,
+                ..Default::default()
+            }))
+            .await?;
+        let mut removed = 0;
+        for container in containers {
+            let Some(id) = container.id else {
+                continue;
+            };
+            log::info!("Removing container {} left behind by test {}", id, test_id);
+            docker
+                .remove_container(
+                    &id,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        ..Default::default()
+                    }),
+                )
+                .await?;
+            removed += 1;
+        }
+        Ok(removed)
     }
     // This does not have to be mutable but I am using the borrow checker to ensure
     // this isn't concurrently modified
@@ -86,6 +252,79 @@ impl DockerEnvironment {
         Ok(())
     }
 }
+
+// Percent of one core used since the previous sample, the same figure `docker stats` shows
+pub fn cpu_load(cpu: &CPUStats, precpu: &CPUStats) -> Option<f32> {
+    let cpu_delta = cpu
+        .cpu_usage
+        .total_usage
+        .checked_sub(precpu.cpu_usage.total_usage)?;
+    let system_delta = cpu
+        .system_cpu_usage?
+        .checked_sub(precpu.system_cpu_usage?)?;
+    if system_delta == 0 {
+        return None;
+    }
+    let cpus = cpu.online_cpus.or_else(|| {
+        let percpu = cpu.cpu_usage.percpu_usage.as_ref()?;
+        Some(percpu.len() as u64)
+    })?;
+    Some((cpu_delta as f64 / system_delta as f64 * cpus as f64 * 100.0) as f32)
+}
+
+// Resident memory, leaving out the page cache which the kernel can take back
+pub fn ram_usage_bytes(memory: &MemoryStats) -> Option<u64> {
+    match memory.stats {
+        Some(MemoryStatsStats::V1(stats)) => Some(stats.rss),
+        Some(MemoryStatsStats::V2(stats)) => Some(stats.anon),
+        None => memory.usage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bollard::container::{CPUUsage, ThrottlingData};
+
+    use super::*;
+
+    fn cpu_stats(total_usage: u64, system_cpu_usage: u64) -> CPUStats {
+        CPUStats {
+            cpu_usage: CPUUsage {
+                percpu_usage: Some(vec![0; 4]),
+                usage_in_usermode: 0,
+                total_usage,
+                usage_in_kernelmode: 0,
+            },
+            system_cpu_usage: Some(system_cpu_usage),
+            online_cpus: None,
+            throttling_data: ThrottlingData {
+                periods: 0,
+                throttled_periods: 0,
+                throttled_time: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn test_cpu_load() {
+        // A quarter of the machine's time across 4 cpus is one whole core
+        let load = cpu_load(&cpu_stats(1250, 5000), &cpu_stats(1000, 4000));
+        assert_eq!(load, Some(100.0));
+        // The first sample has nothing to compare against
+        assert_eq!(
+            cpu_load(&cpu_stats(1000, 4000), &cpu_stats(1000, 4000)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ram_usage_falls_back_to_usage() {
+        let memory: MemoryStats =
+            serde_json::from_value(serde_json::json!({ "usage": 4096 })).unwrap();
+        assert_eq!(ram_usage_bytes(&memory), Some(4096));
+    }
+}
+
 // pub async fn main2() -> Result<(), Box<dyn std::error::Error + 'static>> {
 //     log::info!("Started main2");
 //     log::info!("Container started");
@@ -1,4 +1,6 @@
-mod docker;
+use std::{future::Future, time::Duration};
+
+pub mod docker;
 pub mod docker_env;
 
 pub trait Environment {
@@ -6,3 +8,190 @@ pub trait Environment {
     fn run_script(&mut self, cmd: &str);
     fn cleanup(&mut self);
 }
+
+// Returned by an environment when the work it was doing
+// was stopped because its cancellation token fired
+#[derive(thiserror::Error, Debug)]
+#[error("Execution was cancelled")]
+pub struct Cancelled;
+
+// Returned when work in an environment ran longer than it was allowed to
+#[derive(thiserror::Error, Debug)]
+#[error("Timed out after {}s", .0.as_secs())]
+pub struct TimedOut(pub Duration);
+
+// Everything a finished command left behind
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecOutput {
+    pub exit_code: i64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+    // Turns a non-zero exit code into a NonZeroExit error
+    pub fn check(self) -> anyhow::Result<ExecOutput> {
+        if self.success() {
+            return Ok(self);
+        }
+        Err(NonZeroExit {
+            exit_code: self.exit_code,
+            stderr_tail: tail(&self.stderr, STDERR_TAIL_LINES),
+        }
+        .into())
+    }
+}
+
+// How much of stderr is kept in a NonZeroExit so that the
+// reason a build failed shows up next to the failure
+const STDERR_TAIL_LINES: usize = 20;
+
+#[derive(thiserror::Error, Debug)]
+#[error("Exited with code {exit_code}: {stderr_tail}")]
+pub struct NonZeroExit {
+    pub exit_code: i64,
+    pub stderr_tail: String,
+}
+
+fn tail(output: &str, lines: usize) -> String {
+    let all: Vec<&str> = output.trim_end().lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+// Lines longer than this are split into several
+pub const MAX_LOG_LINE_BYTES: usize = 16 * 1024;
+
+// Turns chunks of command output into lines.
+// A chunk can end part way through a line, so whatever follows the
+// last newline is held back until the rest of the line arrives.
+#[derive(Debug, Default)]
+pub struct LineSplitter {
+    partial: Vec<u8>,
+}
+
+impl LineSplitter {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for byte in chunk {
+            if *byte == b'\n' {
+                lines.push(self.take_line(self.partial.len()));
+                continue;
+            }
+            self.partial.push(*byte);
+            if self.partial.len() >= MAX_LOG_LINE_BYTES {
+                // Split on a char boundary so that multi-byte characters survive
+                let mut split_at = self.partial.len();
+                while split_at > 1 && (self.partial[split_at - 1] & 0xC0) == 0x80 {
+                    split_at -= 1;
+                }
+                if (self.partial[split_at - 1] & 0xC0) == 0xC0 {
+                    split_at -= 1;
+                }
+                if split_at == 0 {
+                    split_at = self.partial.len();
+                }
+                lines.push(self.take_line(split_at));
+            }
+        }
+        lines
+    }
+    // Whatever is left once the output has ended
+    pub fn finish(&mut self) -> Option<String> {
+        if self.partial.is_empty() {
+            return None;
+        }
+        Some(self.take_line(self.partial.len()))
+    }
+    fn take_line(&mut self, len: usize) -> String {
+        let mut line: Vec<u8> = self.partial.drain(..len).collect();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        String::from_utf8_lossy(&line).into_owned()
+    }
+}
+
+// Gives up on `work` with TimedOut once `limit` has passed.
+// This only stops waiting. Callers must shut the environment down
+// to kill whatever was still running inside it.
+pub async fn with_timeout<T>(
+    limit: Option<Duration>,
+    work: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let Some(limit) = limit else {
+        return work.await;
+    };
+    match tokio::time::timeout(limit, work).await {
+        Ok(result) => result,
+        Err(_) => Err(TimedOut(limit).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_with_timeout() {
+        let hung = with_timeout(Some(Duration::from_millis(10)), async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            anyhow::Ok(())
+        })
+        .await;
+        assert!(hung.unwrap_err().downcast_ref::<TimedOut>().is_some());
+
+        let quick = with_timeout(Some(Duration::from_secs(60)), async { anyhow::Ok(1) }).await;
+        assert_eq!(quick.unwrap(), 1);
+        let unlimited = with_timeout(None, async { anyhow::Ok(2) }).await;
+        assert_eq!(unlimited.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_line_splitter_partial_lines() {
+        let mut splitter = LineSplitter::default();
+        assert_eq!(splitter.push(b"first\nsec"), vec!["first"]);
+        assert!(splitter.push(b"ond").is_empty());
+        assert_eq!(splitter.push(b"\r\nthird\n\n"), vec!["second", "third", ""]);
+        assert_eq!(splitter.finish(), None);
+        splitter.push(b"no newline");
+        assert_eq!(splitter.finish().as_deref(), Some("no newline"));
+    }
+
+    #[test]
+    fn test_line_splitter_long_lines() {
+        let mut splitter = LineSplitter::default();
+        // One byte short of the limit, followed by a two byte character
+        let mut long = vec![b'a'; MAX_LOG_LINE_BYTES - 1];
+        long.extend_from_slice("é".as_bytes());
+        long.push(b'\n');
+        let lines = splitter.push(&long);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), MAX_LOG_LINE_BYTES - 1);
+        assert_eq!(lines[1], "é");
+    }
+
+    #[test]
+    fn test_exec_output_check() {
+        let ok = ExecOutput {
+            exit_code: 0,
+            stdout: "done".into(),
+            stderr: String::new(),
+        };
+        assert_eq!(ok.clone().check().unwrap(), ok);
+
+        let stderr: Vec<String> = (0..30).map(|line| format!("line {}", line)).collect();
+        let failed = ExecOutput {
+            exit_code: 2,
+            stdout: String::new(),
+            stderr: stderr.join("\n"),
+        };
+        let err = failed.check().unwrap_err();
+        let err = err.downcast_ref::<NonZeroExit>().unwrap();
+        assert_eq!(err.exit_code, 2);
+        assert!(err.stderr_tail.starts_with("line 10\n"));
+        assert!(err.stderr_tail.ends_with("line 29"));
+    }
+}
@@ -2,10 +2,7 @@ use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 
-use crate::config::{
-    orchestrator_config::{self, OrchestratorConfig},
-    tests::TestSpecificationID,
-};
+use crate::config::{orchestrator_config::OrchestratorConfig, tests::TestSpecificationID};
 #[derive(Debug)]
 pub struct HtpFolder(pub PathBuf, pub FolderType);
 
@@ -24,6 +21,8 @@ pub enum DependencyFolderType {
 pub enum TestFolderType {
     Config,
     Persist,
+    // Holds STATS_FILE_NAME, which the test writes its own statistics to
+    Stats,
 }
 
 impl HtpFolder {
@@ -57,6 +56,7 @@ impl HtpFolder {
         path.push(match folder_type {
             TestFolderType::Config => "config",
             TestFolderType::Persist => "persist",
+            TestFolderType::Stats => "stats",
         });
         log::info!("Creating {:?}", &path);
         std::fs::create_dir_all(&path).context("Cannot create {path}")?;
@@ -91,3 +91,32 @@ fn copy_dir_recurse(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> std::io::Re
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::orchestrator_config;
+
+    #[test]
+    fn test_new_test_folders_are_per_run() {
+        let mut config =
+            orchestrator_config::parse(&PathBuf::from("../example_config/orchestrator.json5"))
+                .unwrap();
+        config.htp_folder_root =
+            std::env::temp_dir().join(format!("htp-folder-{}", std::process::id()));
+        let test_spec_id = ("general".to_string(), "simpleconn".to_string());
+
+        let first =
+            HtpFolder::new_test(&config, TestFolderType::Config, &test_spec_id, "run-a").unwrap();
+        let second =
+            HtpFolder::new_test(&config, TestFolderType::Config, &test_spec_id, "run-b").unwrap();
+        assert_ne!(first.0, second.0);
+        assert_eq!(
+            first.0,
+            config
+                .htp_folder_root
+                .join("tests/general/simpleconn-run-a/config")
+        );
+        std::fs::remove_dir_all(&config.htp_folder_root).unwrap();
+    }
+}
@@ -1,12 +1,13 @@
 use std::{
+    collections::HashMap,
     marker::PhantomData,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
     time::SystemTime,
 };
 
 use anyhow::{anyhow, Context};
-use elasticsearch::{http::transport::Transport, Elasticsearch};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     config::{
@@ -16,26 +17,125 @@ use crate::{
         tests::{TestGroup, TestSpecification, TestSpecificationID},
         Config,
     },
-    environment::docker_env::DockerEnvironment,
+    environment::{docker_env::DockerEnvironment, with_timeout, Cancelled, TimedOut},
     folder::{DependencyFolderType, HtpFolder, TestFolderType},
+    priority_queue::Prioritized,
+    resource_ledger::LeaseRenewal,
+    resources::ResourceClaim,
     running_test_map::{RunningTestMap, RunningTestMapEntry},
-    statistics::{DbWrapper, WrapperType},
+    statistics::{DbWrapper, TestCompletionEntry, TestStageSucessEntry, UtilizationSummary},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TestPriority(usize, &'static str);
 
+impl TestPriority {
+    pub fn level(&self) -> usize {
+        self.0
+    }
+    pub fn name(&self) -> &'static str {
+        self.1
+    }
+    pub fn from_level(level: usize) -> Option<TestPriority> {
+        match level {
+            0 => Some(PRIORITY_ADMIN),
+            1 => Some(PRIORITY_MANUAL_ONESHOT),
+            2 => Some(PRIORITY_MANUAL),
+            3 => Some(PRIORITY_CI),
+            4 => Some(PRIORITY_CANARY),
+            _ => None,
+        }
+    }
+}
+
+impl<Stage> Prioritized for HtpTest<Stage> {
+    fn priority_level(&self) -> usize {
+        self.priority.level()
+    }
+}
+
+// Lower levels are run first
 pub const PRIORITY_ADMIN: TestPriority = TestPriority(0, "Admin (Manual)");
 // When a human wants to run a single test on a single device
-// pub const PRIORITY_MANUAL_ONESHOT: TestPriority = TestPriority(1, "Highest (Manual)");
-// // When a human wants to run a test on many devices
-// pub const PRIORITY_MANUAL: TestPriority = TestPriority(2, "High (Manual)");
-// // If this ever gets used for CI
-// pub const PRIORITY_CI: TestPriority = TestPriority(3, "Medium (CI)");
-// // Constant automatic background checks when dependencies update
-// pub const PRIORITY_CANARY: TestPriority = TestPriority(4, "Low (Canary)");
+pub const PRIORITY_MANUAL_ONESHOT: TestPriority = TestPriority(1, "Highest (Manual)");
+// When a human wants to run a test on many devices
+pub const PRIORITY_MANUAL: TestPriority = TestPriority(2, "High (Manual)");
+// If this ever gets used for CI
+pub const PRIORITY_CI: TestPriority = TestPriority(3, "Medium (CI)");
+// Constant automatic background checks when dependencies update
+pub const PRIORITY_CANARY: TestPriority = TestPriority(4, "Low (Canary)");
+
+// Every run of a test gets its own globally unique id.
+// It names the run's folders and is attached to everything the run records.
+pub type TestID = String;
+
+pub fn new_test_id() -> TestID {
+    uuid::Uuid::new_v4().to_string()
+}
 
-pub type TestID = usize;
+pub const STATS_FILE_NAME: &str = "stats.jsonl";
+
+// Why a test ended up in the Terminated stage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TerminationReason {
+    // The test script ran and exited with 0
+    Finished,
+    // Something other than the test script went wrong,
+    // so the test never got a fair chance to pass
+    Failed(String),
+    // The test script ran and exited with a non-zero code
+    TestFailed { exit_code: i64 },
+    // The test, or one of its builds or installs, ran past its time limit
+    TimedOut(String),
+    OrchestratorShutdown,
+    // Someone asked for the test to be cancelled
+    Cancelled,
+    // The orchestrator went down while the test may have been running
+    OrchestratorCrashed,
+}
+
+impl TerminationReason {
+    pub fn passed(&self) -> bool {
+        *self == TerminationReason::Finished
+    }
+    pub fn from_error(msg: &str, err: &anyhow::Error) -> Self {
+        if err.downcast_ref::<Cancelled>().is_some() {
+            return TerminationReason::OrchestratorShutdown;
+        }
+        if err.downcast_ref::<TimedOut>().is_some() {
+            return TerminationReason::TimedOut(format!("{}: {:#}", msg, err));
+        }
+        TerminationReason::Failed(format!("{}: {:#}", msg, err))
+    }
+    // A short name for grouping outcomes, e.g. in metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TerminationReason::Finished => "finished",
+            TerminationReason::Failed(_) => "failed",
+            TerminationReason::TestFailed { .. } => "test_failed",
+            TerminationReason::TimedOut(_) => "timed_out",
+            TerminationReason::OrchestratorShutdown => "orchestrator_shutdown",
+            TerminationReason::Cancelled => "cancelled",
+            TerminationReason::OrchestratorCrashed => "orchestrator_crashed",
+        }
+    }
+}
+
+impl std::fmt::Display for TerminationReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TerminationReason::Finished => write!(f, "finished"),
+            TerminationReason::Failed(msg) => write!(f, "failed: {}", msg),
+            TerminationReason::TestFailed { exit_code } => {
+                write!(f, "test failed with exit code {}", exit_code)
+            }
+            TerminationReason::TimedOut(msg) => write!(f, "timed out: {}", msg),
+            TerminationReason::OrchestratorShutdown => write!(f, "orchestrator shutdown"),
+            TerminationReason::Cancelled => write!(f, "cancelled"),
+            TerminationReason::OrchestratorCrashed => write!(f, "orchestrator crashed"),
+        }
+    }
+}
 
 // Using some fancy Rust generics & type system magic,
 // we only expose certain methods on tests
@@ -105,16 +205,31 @@ pub struct HtpTest<Stage = Queued> {
 
     pub config_folder: HtpFolder,
     pub persist_folder: HtpFolder,
+    pub stats_folder: HtpFolder,
 
     pub config: Option<Config>,
     pub dependencies: Option<Vec<Dependency>>,
     pub test_spec_id: TestSpecificationID,
     pub priority: TestPriority,
+    // What the Aquirer locked for this test. Set once the test is Runnable.
+    pub claim: Option<ResourceClaim>,
+    // Keeps the claim's leases alive for as long as the test exists
+    pub lease: Option<LeaseRenewal>,
 
     pub stats_sink: DbWrapper,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+    pub execution_start_time: Option<chrono::DateTime<chrono::Utc>>,
+    // Each stage marks itself here once it has done its job
+    pub stage_success: TestStageSucessEntry,
+    // Filled in by sampling the container while the test runs
+    pub utilization: UtilizationSummary,
 
     pub error: Option<anyhow::Error>,
+    pub termination_reason: Option<TerminationReason>,
     pub test_map: Arc<Mutex<RunningTestMap>>,
+    // Cancelled when the orchestrator is shutting down or the test is cancelled by id.
+    // Anything long-running that the test does should stop when this fires.
+    pub cancel: CancellationToken,
 
     pub stage: std::marker::PhantomData<Stage>,
 }
@@ -123,79 +238,156 @@ impl<Stage> HtpTest<Stage>
 where
     Stage: TestStage,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config_folder_path: &PathBuf,
         orchestrator_config: OrchestratorConfig,
+        test_id: TestID,
         test_spec_id: TestSpecificationID,
         priority: TestPriority,
         test_map: Arc<Mutex<RunningTestMap>>,
+        stats_sink: DbWrapper,
+        cancel: CancellationToken,
     ) -> anyhow::Result<HtpTest<Queued>> {
-        let test_id = 0; // TODO
         let config_folder = HtpFolder::new_test(
             &orchestrator_config,
             TestFolderType::Config,
             &test_spec_id,
-            "0",
+            &test_id,
         )?;
         config_folder.copy_from(config_folder_path)?;
         let persist_folder = HtpFolder::new_test(
             &orchestrator_config,
             TestFolderType::Persist,
             &test_spec_id,
-            "0",
+            &test_id,
+        )?;
+        let stats_folder = HtpFolder::new_test(
+            &orchestrator_config,
+            TestFolderType::Stats,
+            &test_spec_id,
+            &test_id,
         )?;
+        // Docker can only bind mount a file that already exists
+        std::fs::File::create(stats_folder.0.join(STATS_FILE_NAME))
+            .context("Cannot create the stats file")?;
         {
             let mut map = test_map.lock().unwrap();
-            map.map.push(RunningTestMapEntry {
-                id: test_spec_id.clone(),
-                ver: "0".into(),
-                stage: Queued::name(),
-                entry_time: SystemTime::now(),
-            });
+            map.insert(
+                priority.level(),
+                RunningTestMapEntry {
+                    id: test_spec_id.clone(),
+                    ver: test_id.clone(),
+                    stage: Queued::name(),
+                    entry_time: SystemTime::now(),
+                    cancel: cancel.clone(),
+                    cancel_requested: false,
+                },
+            );
         }
 
-        let transport = Transport::single_node(&orchestrator_config.elastic_addr)?;
-        let client = Elasticsearch::new(transport);
         Ok(HtpTest {
             id: test_id,
             config_folder,
             persist_folder,
+            stats_folder,
             orchestrator_config,
             config: None,
             dependencies: None,
             test_spec_id,
             priority,
-            stats_sink: DbWrapper::new_elasticsearch(WrapperType::Test, "Chicken".into(), client),
+            claim: None,
+            lease: None,
+            stats_sink,
+            creation_time: chrono::offset::Utc::now(),
+            execution_start_time: None,
+            stage_success: TestStageSucessEntry::default(),
+            utilization: UtilizationSummary::default(),
             error: None,
+            termination_reason: None,
             test_map,
-            stage: PhantomData::default(),
+            cancel,
+            stage: PhantomData,
         })
     }
 
     pub fn clone_into<T: TestStage>(self) -> HtpTest<T> {
-        {
+        let left = {
             let mut map = self.test_map.lock().unwrap();
-            let mut map_entry: &mut RunningTestMapEntry = map
-                .map
-                .iter_mut()
-                .find(|p| p.id == self.test_spec_id)
-                .unwrap();
-            map_entry.stage = T::name();
-            map_entry.entry_time = SystemTime::now();
+            map.set_stage(&self.id, T::name())
+        };
+        if let (Some((left, spent)), Some(metrics)) = (left, self.stats_sink.metrics()) {
+            metrics.stage_finished(&left, spent);
         }
         HtpTest {
             id: self.id,
             config_folder: self.config_folder,
             persist_folder: self.persist_folder,
+            stats_folder: self.stats_folder,
             orchestrator_config: self.orchestrator_config,
             config: self.config,
             dependencies: self.dependencies,
             test_spec_id: self.test_spec_id,
             priority: self.priority,
+            claim: self.claim,
+            lease: self.lease,
             stats_sink: self.stats_sink,
+            creation_time: self.creation_time,
+            execution_start_time: self.execution_start_time,
+            stage_success: self.stage_success,
+            utilization: self.utilization,
             error: self.error,
+            termination_reason: self.termination_reason,
             test_map: self.test_map,
-            stage: PhantomData::default(),
+            cancel: self.cancel,
+            stage: PhantomData,
+        }
+    }
+
+    pub fn terminate(mut self, reason: TerminationReason) -> HtpTest<Terminated> {
+        self.termination_reason = Some(reason);
+        self.clone_into()
+    }
+}
+
+impl HtpTest<Terminated> {
+    // The type of the device the test was given, if it got that far
+    pub fn device_type(&self) -> Option<&str> {
+        let device = &self.claim.as_ref()?.device;
+        let config = self.config.as_ref()?;
+        Some(&config.devices.get(device)?.device_type)
+    }
+    pub fn completion_entry(&self) -> TestCompletionEntry {
+        let test_config = self.config.as_ref().and_then(|config| {
+            config
+                .tests
+                .get(&self.test_spec_id.0)?
+                .get_test(&self.test_spec_id.1)
+                .cloned()
+        });
+        let dependencies = self
+            .dependencies
+            .iter()
+            .flatten()
+            .map(|dep| format!("{}-{}", dep.name, dep.ver))
+            .collect();
+        let reason = self
+            .termination_reason
+            .clone()
+            .unwrap_or_else(|| TerminationReason::Failed("Unknown".into()));
+        TestCompletionEntry {
+            t_id: self.id.clone(),
+            test_name: format!("{}/{}", self.test_spec_id.0, self.test_spec_id.1),
+            creation_time: self.creation_time,
+            execution_start_time: self.execution_start_time,
+            termination_time: chrono::offset::Utc::now(),
+            passed: reason.passed(),
+            termination_reason: reason.to_string(),
+            stage_success: self.stage_success.clone(),
+            test_config,
+            dependencies,
+            peak_ram_usage_megabytes: self.utilization.peak_ram_usage_megabytes(),
+            mean_cpu_load: self.utilization.mean_cpu_load(),
         }
     }
 }
@@ -233,7 +425,7 @@ where
             .as_mut()
             .unwrap_or_else(|| panic!("Config got past validation without creating dependencies"))
     }
-    pub fn test_mount_map(&self, inner_htp_root: &PathBuf) -> EnvironmentMountMap {
+    pub fn test_mount_map(&self, inner_htp_root: &Path) -> EnvironmentMountMap {
         let mut map = EnvironmentMountMap::new();
         map.0.push(MountMapSet {
             env_var: "HTP_CONFIG".into(),
@@ -246,8 +438,31 @@ where
             host_path: self.persist_folder.0.clone(),
             inner_path: inner_htp_root.join("persist"),
         });
+
+        // Tests append one json value per line to this file
+        // and the orchestrator forwards them to the stats index
+        map.0.push(MountMapSet {
+            env_var: "HTP_STATS".into(),
+            host_path: self.stats_file(),
+            inner_path: inner_htp_root.join(STATS_FILE_NAME),
+        });
         map
     }
+    pub fn stats_file(&self) -> PathBuf {
+        self.stats_folder.0.join(STATS_FILE_NAME)
+    }
+}
+
+// Every test that needs a dependency builds it into the same folders, so builds of
+// one dependency take turns and nothing installs from the folder while it is being built.
+// Holds whether the last build into the folder succeeded.
+pub type BuildLock = Arc<tokio::sync::RwLock<bool>>;
+
+static BUILD_LOCKS: OnceLock<Mutex<HashMap<PathBuf, BuildLock>>> = OnceLock::new();
+
+fn build_lock(build_output_folder: &Path) -> BuildLock {
+    let mut locks = BUILD_LOCKS.get_or_init(Default::default).lock().unwrap();
+    Arc::clone(locks.entry(build_output_folder.to_path_buf()).or_default())
 }
 
 #[derive(Debug)]
@@ -257,6 +472,7 @@ pub struct Dependency {
     pub spec: DependencySpecification,
     pub build_input_folder: HtpFolder,
     pub build_output_folder: HtpFolder,
+    pub build_lock: BuildLock,
 }
 impl Dependency {
     pub fn new(
@@ -284,11 +500,12 @@ impl Dependency {
             name: name.into(),
             ver: ver.into(),
             spec: specification.clone(),
+            build_lock: build_lock(&build_output_folder.0),
             build_input_folder,
             build_output_folder,
         })
     }
-    pub fn dependency_mount_map(&self, inner_mount_root: &PathBuf) -> EnvironmentMountMap {
+    pub fn dependency_mount_map(&self, inner_mount_root: &Path) -> EnvironmentMountMap {
         let mut map = EnvironmentMountMap::default();
 
         let inner_root_path = inner_mount_root
@@ -308,35 +525,58 @@ impl Dependency {
         map
     }
 
-    // TODO: add protection to ensure this isn't called multiple times at once
-    pub async fn build(&self, build_target_type: &DeviceType) -> anyhow::Result<()> {
+    // Waits for any other build of this dependency to finish first
+    pub async fn build(
+        &self,
+        build_target_type: &DeviceType,
+        test_id: &str,
+        cancel: &CancellationToken,
+        logs: &DbWrapper,
+    ) -> anyhow::Result<()> {
+        let mut built = tokio::select! {
+            built = self.build_lock.write() => built,
+            _ = cancel.cancelled() => return Err(Cancelled.into()),
+        };
+        // Whatever happens next leaves the output half written until it finishes
+        *built = false;
         if let DeviceClassification::Docker(spec) = &build_target_type.classification {
             log::info!("Starting docker container");
             let mount_map = self.dependency_mount_map(&spec.htp_root);
             let container_config = bollard::container::Config {
                 image: Some(spec.image.clone()),
                 tty: Some(true),
+                labels: Some(DockerEnvironment::test_labels(test_id)),
                 host_config: Some(bollard::service::HostConfig {
                     binds: Some(mount_map.mount_points()?),
                     ..Default::default()
                 }),
                 ..Default::default()
             };
-            let mut env = DockerEnvironment::new(&spec, container_config).await?;
-
-            env.exec(bollard::exec::CreateExecOptions {
-                env: Some(mount_map.env_vars()?),
-                cmd: Some(vec![
-                    "/bin/bash".into(),
-                    "-c".into(),
-                    self.spec.build_script.clone(),
-                ]),
-                ..Default::default()
-            })
-            .await?;
+            let mut env = DockerEnvironment::new(spec, container_config, cancel.clone()).await?;
+
+            let build_result = with_timeout(
+                self.spec.build_timeout(),
+                env.exec(
+                    bollard::exec::CreateExecOptions {
+                        env: Some(mount_map.env_vars()?),
+                        cmd: Some(vec![
+                            "/bin/bash".into(),
+                            "-c".into(),
+                            self.spec.build_script.clone(),
+                        ]),
+                        ..Default::default()
+                    },
+                    logs,
+                ),
+            )
+            .await
+            .and_then(|output| output.check())
+            .context("Build script failed");
             // env.exec(&dep.spec.build_script).await.unwrap();
+            // Always remove the container, even if the build failed, timed out or was cancelled
             env.shutdown().await?;
-            return Ok(());
+            *built = build_result.is_ok();
+            return build_result.map(|_| ());
         }
         todo!()
     }
@@ -344,18 +584,35 @@ impl Dependency {
         &self,
         spec: &DockerSpec,
         env: &mut DockerEnvironment,
+        logs: &DbWrapper,
     ) -> anyhow::Result<()> {
+        // Held until the install is done so another test cannot rebuild underneath it
+        let built = self.build_lock.read().await;
+        if !*built {
+            return Err(anyhow!(
+                "The last build of {} failed or was interrupted, so there is nothing to install",
+                self.name
+            ));
+        }
         let mount_map = self.dependency_mount_map(&spec.htp_root);
-        env.exec(bollard::exec::CreateExecOptions {
-            env: Some(mount_map.env_vars()?),
-            cmd: Some(vec![
-                "/bin/bash".into(),
-                "-c".into(),
-                self.spec.install_script.clone(),
-            ]),
-            ..Default::default()
-        })
-        .await?;
+        with_timeout(
+            self.spec.install_timeout(),
+            env.exec(
+                bollard::exec::CreateExecOptions {
+                    env: Some(mount_map.env_vars()?),
+                    cmd: Some(vec![
+                        "/bin/bash".into(),
+                        "-c".into(),
+                        self.spec.install_script.clone(),
+                    ]),
+                    ..Default::default()
+                },
+                logs,
+            ),
+        )
+        .await?
+        .check()
+        .context("Install script failed")?;
 
         Ok(())
     }
@@ -402,3 +659,47 @@ impl EnvironmentMountMap {
         Ok(mount_points)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_termination_reason_from_error() {
+        let err = anyhow::Error::from(Cancelled).context("Failed to install dep");
+        assert_eq!(
+            TerminationReason::from_error("Failed to run test", &err),
+            TerminationReason::OrchestratorShutdown
+        );
+
+        let err = anyhow!("exit code 1");
+        assert_eq!(
+            TerminationReason::from_error("Failed to run test", &err),
+            TerminationReason::Failed("Failed to run test: exit code 1".into())
+        );
+
+        let err = anyhow::Error::from(TimedOut(std::time::Duration::from_secs(600)));
+        assert_eq!(
+            TerminationReason::from_error("Failed to run test", &err),
+            TerminationReason::TimedOut("Failed to run test: Timed out after 600s".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_lock_shared_per_folder() {
+        let folder = PathBuf::from("/htp/dependencies/output/viam-VER");
+        let lock = build_lock(&folder);
+        assert!(Arc::ptr_eq(&lock, &build_lock(&folder)));
+        assert!(!Arc::ptr_eq(
+            &lock,
+            &build_lock(Path::new("/htp/dependencies/output/other-VER"))
+        ));
+
+        // A second build waits for the first to finish
+        let building = lock.write().await;
+        assert!(build_lock(&folder).try_write().is_err());
+        assert!(build_lock(&folder).try_read().is_err());
+        drop(building);
+        assert!(!*build_lock(&folder).read().await);
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+
+use crate::config::snapshots::{self, Snapshot, SnapshotMap};
+
+// The computer-edited files in <config>/internal/.
+// Every change goes through here so that two edits
+// arriving at once cannot overwrite each other.
+#[derive(Debug)]
+pub struct Inventory {
+    folder: PathBuf,
+    lock: Mutex<()>,
+}
+
+// Returned when a device already has the snapshot being added
+#[derive(thiserror::Error, Debug)]
+#[error("Device {device} already has the snapshot {path}")]
+pub struct SnapshotExists {
+    pub device: String,
+    pub path: String,
+}
+
+impl Inventory {
+    pub fn new(config_path: &Path) -> Self {
+        Self {
+            folder: config_path.join("internal"),
+            lock: Mutex::new(()),
+        }
+    }
+    fn snapshots_path(&self) -> PathBuf {
+        self.folder.join("snapshots.json5")
+    }
+    pub fn snapshots(&self) -> anyhow::Result<SnapshotMap> {
+        let path = self.snapshots_path();
+        if !path.exists() {
+            return Ok(SnapshotMap::new());
+        }
+        snapshots::parse(&path).with_context(|| format!("Failed to read {:?}", path))
+    }
+    pub fn add_snapshot(&self, device: &str, snapshot: Snapshot) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        let mut all = self.snapshots()?;
+        let snapshots = &mut all.entry(device.into()).or_default().snapshots;
+        if snapshots.contains(&snapshot) {
+            return Err(SnapshotExists {
+                device: device.into(),
+                path: snapshot.path,
+            }
+            .into());
+        }
+        snapshots.push(snapshot);
+        self.write_snapshots(&all)
+    }
+    // Returns false if the device had no such snapshot
+    pub fn remove_snapshot(&self, device: &str, snapshot: &Snapshot) -> anyhow::Result<bool> {
+        let _lock = self.lock.lock().unwrap();
+        let mut all = self.snapshots()?;
+        let Some(device_snapshots) = all.get_mut(device) else {
+            return Ok(false);
+        };
+        let before = device_snapshots.snapshots.len();
+        device_snapshots
+            .snapshots
+            .retain(|existing| existing != snapshot);
+        if device_snapshots.snapshots.len() == before {
+            return Ok(false);
+        }
+        if device_snapshots.snapshots.is_empty() {
+            all.remove(device);
+        }
+        self.write_snapshots(&all)?;
+        Ok(true)
+    }
+    fn write_snapshots(&self, snapshots: &SnapshotMap) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.folder)
+            .with_context(|| format!("Cannot create {:?}", self.folder))?;
+        let path = self.snapshots_path();
+        snapshots::write(&path, snapshots).with_context(|| format!("Failed to write {:?}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshots() {
+        let config = std::env::temp_dir().join(format!("htp-inventory-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&config);
+        let inventory = Inventory::new(&config);
+        assert!(inventory.snapshots().unwrap().is_empty());
+
+        let snapshot = Snapshot {
+            path: "base.img".into(),
+        };
+        inventory.add_snapshot("pi4", snapshot.clone()).unwrap();
+        let exists = inventory.add_snapshot("pi4", snapshot.clone()).unwrap_err();
+        assert!(exists.downcast_ref::<SnapshotExists>().is_some());
+        assert_eq!(
+            inventory.snapshots().unwrap()["pi4"].snapshots,
+            vec![snapshot.clone()]
+        );
+
+        assert!(!inventory.remove_snapshot("pi3", &snapshot).unwrap());
+        assert!(inventory.remove_snapshot("pi4", &snapshot).unwrap());
+        assert!(inventory.snapshots().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&config);
+    }
+}
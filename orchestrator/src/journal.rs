@@ -0,0 +1,343 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread::JoinHandle,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::tests::TestSpecificationID,
+    htp_test::{Queued, Runnable, Terminated, TestID, TestStage},
+};
+
+// The journal is an append-only record of every test submission
+// and stage transition. It is the only piece of queue state that
+// survives the orchestrator crashing or the host rebooting.
+//
+// One json object per line so that a write torn by a crash
+// can only ever damage the last line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Submitted {
+        id: TestID,
+        test_spec_id: TestSpecificationID,
+        priority: usize,
+        time: chrono::DateTime<chrono::Utc>,
+    },
+    StageChanged {
+        id: TestID,
+        stage: String,
+        time: chrono::DateTime<chrono::Utc>,
+    },
+}
+
+// A test that was submitted but never reached the Terminated stage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournaledTest {
+    pub id: TestID,
+    pub test_spec_id: TestSpecificationID,
+    pub priority: usize,
+    pub stage: String,
+}
+
+impl JournaledTest {
+    // Once the Aquirer has handed a test its resources it may be holding
+    // hardware or have a running environment, so it cannot simply be queued again.
+    pub fn may_have_been_running(&self) -> bool {
+        self.stage == Runnable::name()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct JournalReplay {
+    // In the order they were originally submitted
+    pub unfinished: Vec<JournaledTest>,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    // Replays whatever the previous orchestrator left behind,
+    // compacts the journal down to only the unfinished tests
+    // and opens it for appending.
+    pub fn open(path: &Path) -> anyhow::Result<(Journal, JournalReplay)> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Cannot create {:?}", parent))?;
+        }
+        let replay = if path.exists() {
+            replay(path)?
+        } else {
+            JournalReplay::default()
+        };
+
+        // Write the compacted journal next to the old one and swap it in
+        // so that crashing part way through leaves the old journal intact
+        let tmp_path = path.with_extension("jsonl.tmp");
+        {
+            let mut tmp = File::create(&tmp_path)
+                .with_context(|| format!("Cannot create {:?}", &tmp_path))?;
+            for test in &replay.unfinished {
+                let time = chrono::offset::Utc::now();
+                write_entry(
+                    &mut tmp,
+                    &JournalEntry::Submitted {
+                        id: test.id.clone(),
+                        test_spec_id: test.test_spec_id.clone(),
+                        priority: test.priority,
+                        time,
+                    },
+                )?;
+                write_entry(
+                    &mut tmp,
+                    &JournalEntry::StageChanged {
+                        id: test.id.clone(),
+                        stage: test.stage.clone(),
+                        time,
+                    },
+                )?;
+            }
+            tmp.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, path).with_context(|| format!("Cannot replace {:?}", path))?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open {:?}", path))?;
+        Ok((
+            Journal {
+                path: path.to_path_buf(),
+                file,
+            },
+            replay,
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&mut self, entry: &JournalEntry) -> anyhow::Result<()> {
+        write_entry(&mut self.file, entry)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Moves the journal onto its own thread so that callers never wait on an fsync
+    pub fn spawn_writer(mut self) -> anyhow::Result<JournalWriter> {
+        let (sender, receiver) = mpsc::channel::<JournalEntry>();
+        let thread = std::thread::Builder::new()
+            .name("htp-journal".into())
+            .spawn(move || {
+                for entry in receiver {
+                    // Losing a journal entry only matters if we also crash,
+                    // so it isn't worth failing the test over
+                    if let Err(err) = self.append(&entry) {
+                        log::error!(
+                            "Failed to write {:?} to journal {:?}: {:?}",
+                            entry,
+                            self.path,
+                            err
+                        );
+                    }
+                }
+            })
+            .context("Cannot start the journal writer")?;
+        Ok(JournalWriter {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+}
+
+// Entries are written in the order they are sent.
+// Dropping the writer waits for everything sent so far to be written.
+#[derive(Debug)]
+pub struct JournalWriter {
+    sender: Option<mpsc::Sender<JournalEntry>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl JournalWriter {
+    pub fn send(&self, entry: JournalEntry) {
+        if let Some(sender) = &self.sender {
+            if sender.send(entry).is_err() {
+                log::error!("The journal writer has stopped");
+            }
+        }
+    }
+}
+
+impl Drop for JournalWriter {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_entry(file: &mut File, entry: &JournalEntry) -> anyhow::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+fn replay(path: &Path) -> anyhow::Result<JournalReplay> {
+    let file = File::open(path).with_context(|| format!("Cannot open {:?}", path))?;
+    let mut tests: Vec<JournaledTest> = Vec::new();
+    for (line_num, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: JournalEntry = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(err) => {
+                // Most likely a write that was cut short by a crash
+                log::warn!(
+                    "Skipping unreadable journal line {} in {:?}: {}",
+                    line_num + 1,
+                    path,
+                    err
+                );
+                continue;
+            }
+        };
+        match entry {
+            JournalEntry::Submitted {
+                id,
+                test_spec_id,
+                priority,
+                ..
+            } => {
+                // A test that is resubmitted after a crash keeps its place in line
+                if let Some(test) = tests.iter_mut().find(|test| test.id == id) {
+                    test.stage = Queued::name();
+                    continue;
+                }
+                tests.push(JournaledTest {
+                    id,
+                    test_spec_id,
+                    priority,
+                    stage: Queued::name(),
+                });
+            }
+            JournalEntry::StageChanged { id, stage, .. } => {
+                if stage == Terminated::name() {
+                    tests.retain(|test| test.id != id);
+                } else if let Some(test) = tests.iter_mut().find(|test| test.id == id) {
+                    test.stage = stage;
+                }
+            }
+        }
+    }
+    Ok(JournalReplay { unfinished: tests })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("htp-journal-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("journal.jsonl")
+    }
+
+    fn submitted(id: &str, name: &str) -> JournalEntry {
+        JournalEntry::Submitted {
+            id: id.into(),
+            test_spec_id: ("general".into(), name.into()),
+            priority: 0,
+            time: chrono::offset::Utc::now(),
+        }
+    }
+
+    fn stage_changed(id: &str, stage: String) -> JournalEntry {
+        JournalEntry::StageChanged {
+            id: id.into(),
+            stage,
+            time: chrono::offset::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_open_empty() {
+        let path = journal_path("empty");
+        let (journal, replay) = Journal::open(&path).unwrap();
+        assert!(replay.unfinished.is_empty());
+        assert!(journal.path().exists());
+    }
+
+    #[test]
+    fn test_replay_unfinished() {
+        let path = journal_path("unfinished");
+        {
+            let (mut journal, _) = Journal::open(&path).unwrap();
+            journal.append(&submitted("a", "finished")).unwrap();
+            journal.append(&submitted("b", "queued")).unwrap();
+            journal.append(&submitted("c", "running")).unwrap();
+            journal
+                .append(&stage_changed("a", Terminated::name()))
+                .unwrap();
+            journal
+                .append(&stage_changed("c", Runnable::name()))
+                .unwrap();
+        }
+        let (_, replay) = Journal::open(&path).unwrap();
+        assert_eq!(replay.unfinished.len(), 2);
+        assert_eq!(replay.unfinished[0].test_spec_id.1, "queued");
+        assert!(!replay.unfinished[0].may_have_been_running());
+        assert_eq!(replay.unfinished[1].test_spec_id.1, "running");
+        assert!(replay.unfinished[1].may_have_been_running());
+
+        // Reopening the compacted journal gives back the same tests
+        let (_, replay_again) = Journal::open(&path).unwrap();
+        assert_eq!(replay.unfinished, replay_again.unfinished);
+    }
+
+    #[test]
+    fn test_writer_writes_everything_before_drop() {
+        let path = journal_path("writer");
+        {
+            let (journal, _) = Journal::open(&path).unwrap();
+            let writer = journal.spawn_writer().unwrap();
+            writer.send(submitted("a", "finished"));
+            writer.send(submitted("b", "running"));
+            writer.send(stage_changed("b", Runnable::name()));
+            writer.send(stage_changed("a", Terminated::name()));
+        }
+        let (_, replay) = Journal::open(&path).unwrap();
+        assert_eq!(replay.unfinished.len(), 1);
+        assert_eq!(replay.unfinished[0].id, "b");
+        assert!(replay.unfinished[0].may_have_been_running());
+    }
+
+    #[test]
+    fn test_replay_torn_write() {
+        let path = journal_path("torn");
+        {
+            let (mut journal, _) = Journal::open(&path).unwrap();
+            journal.append(&submitted("a", "queued")).unwrap();
+        }
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(b"{\"event\":\"stage_chan").unwrap();
+        }
+        let (_, replay) = Journal::open(&path).unwrap();
+        assert_eq!(replay.unfinished.len(), 1);
+        assert_eq!(replay.unfinished[0].stage, Queued::name());
+    }
+}
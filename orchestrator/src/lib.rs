@@ -0,0 +1,28 @@
+pub mod api;
+pub mod api_client;
+pub mod bulk_writer;
+pub mod config;
+pub mod environment;
+pub mod folder;
+//pub mod git;
+pub mod htp_test;
+pub mod inventory;
+pub mod journal;
+// keygen and ssh are not used yet and are left as they were
+#[allow(unused, clippy::all)]
+pub mod keygen;
+pub mod logging;
+pub mod loki;
+pub mod metrics;
+pub mod orchestrator;
+pub mod priority_queue;
+pub mod resource_ledger;
+pub mod resources;
+pub mod running_test_map;
+#[allow(unused, clippy::all)]
+pub mod ssh;
+pub mod stages;
+pub mod statistics;
+pub mod stats_file;
+pub mod stats_sink;
+pub mod test_queue;
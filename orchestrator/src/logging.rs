@@ -0,0 +1,73 @@
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
+
+use crate::{
+    config::orchestrator_config::OrchestratorConfig,
+    loki::{self, Labels, LokiShipper},
+};
+
+// Sends the orchestrator's own logs to stdout, and to Loki unless loki_addr is empty.
+// Returns what test output should be shipped to Loki through, see loki.rs.
+//
+// Both Loki pipelines run as tasks, so this must be called from within the runtime.
+// They drop logs rather than block when Loki is unreachable.
+pub fn init(config: &OrchestratorConfig) -> Option<LokiShipper> {
+    // Same syntax as RUST_LOG for env_logger, e.g. "info,orchestrator=debug"
+    let filter = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|filter| filter.parse::<Targets>().ok())
+        .unwrap_or_else(|| Targets::new().with_default(LevelFilter::INFO));
+
+    let url = match config.loki_addr.as_str() {
+        "" => None,
+        addr => match loki::parse_url(addr) {
+            Ok(url) => Some(url),
+            Err(err) => {
+                eprintln!(
+                    "Not shipping logs to Loki, bad loki_addr {:?}: {}",
+                    addr, err
+                );
+                None
+            }
+        },
+    };
+    let loki_layer = url.clone().and_then(|url| {
+        let built = tracing_loki::builder()
+            .label("host", &config.host_addr)
+            .and_then(|builder| builder.label("service", "htp-orchestrator"))
+            .and_then(|builder| builder.build_url(url));
+        match built {
+            Ok((layer, task)) => {
+                tokio::spawn(task);
+                Some(layer)
+            }
+            Err(err) => {
+                eprintln!("Not shipping orchestrator logs to Loki: {}", err);
+                None
+            }
+        }
+    });
+
+    // Also picks up everything logged through the log crate
+    if let Err(err) = tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(loki_layer)
+        .with(filter)
+        .try_init()
+    {
+        log::warn!("Logging was already set up: {}", err);
+    }
+
+    url.map(|url| {
+        let labels = Labels::from([
+            ("host".into(), config.host_addr.clone()),
+            ("service".into(), "htp-test".into()),
+        ]);
+        let (shipper, pusher) = LokiShipper::new(url, labels);
+        tokio::spawn(pusher.run());
+        shipper
+    })
+}
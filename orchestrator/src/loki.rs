@@ -0,0 +1,264 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+use tracing_loki::url::Url;
+
+// Test output is shipped to Loki in its own streams, labelled with the test
+// it came from, so it can be searched across runs in Grafana.
+//
+// This is best effort. Everything also goes to the stats backend, so when
+// Loki is slow or down lines are dropped rather than ever holding up a test.
+
+pub type Labels = BTreeMap<String, String>;
+
+const QUEUE_SIZE: usize = 4096;
+const BATCH_SIZE: usize = 1000;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct LokiLine {
+    pub labels: Labels,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub line: String,
+}
+
+// Cheap to clone, one is shared by every test
+#[derive(Debug, Clone)]
+pub struct LokiShipper {
+    sender: mpsc::Sender<LokiLine>,
+    labels: Labels,
+    // Lines that did not fit in the queue
+    dropped: Arc<AtomicUsize>,
+}
+
+impl LokiShipper {
+    // `labels` are added to every stream, e.g. the host
+    pub fn new(url: Url, labels: Labels) -> (Self, LokiPusher) {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let pusher = LokiPusher {
+            receiver,
+            dropped: Arc::clone(&dropped),
+            push_url: url.join("loki/api/v1/push").unwrap_or(url),
+            client: reqwest::Client::new(),
+        };
+        (
+            Self {
+                sender,
+                labels,
+                dropped,
+            },
+            pusher,
+        )
+    }
+    pub fn stream(&self, labels: Labels) -> LokiStream {
+        let mut all = self.labels.clone();
+        all.extend(labels);
+        LokiStream {
+            shipper: self.clone(),
+            labels: Arc::new(Mutex::new(all)),
+        }
+    }
+    fn send(&self, line: LokiLine) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// The labels for one test's output. They change as the test moves through
+// the stages, so they are shared between every clone of the test's DbWrapper.
+#[derive(Debug, Clone)]
+pub struct LokiStream {
+    shipper: LokiShipper,
+    labels: Arc<Mutex<Labels>>,
+}
+
+impl LokiStream {
+    pub fn set_label<K: Into<String>, V: Into<String>>(&self, key: K, value: V) {
+        self.labels.lock().unwrap().insert(key.into(), value.into());
+    }
+    pub fn send(&self, is_stderr: bool, line: String, timestamp: chrono::DateTime<chrono::Utc>) {
+        let mut labels = self.labels.lock().unwrap().clone();
+        labels.insert(
+            "stream".into(),
+            if is_stderr { "stderr" } else { "stdout" }.into(),
+        );
+        self.shipper.send(LokiLine {
+            labels,
+            timestamp,
+            line,
+        });
+    }
+}
+
+// The body of one request to Loki's push API
+pub fn push_body(lines: &[LokiLine]) -> Value {
+    let mut streams: BTreeMap<&Labels, Vec<[String; 2]>> = BTreeMap::new();
+    for line in lines {
+        // Only None outside 1677-2262, which Loki could not store anyway
+        let Some(nanos) = line.timestamp.timestamp_nanos_opt() else {
+            continue;
+        };
+        let nanos = nanos.to_string();
+        streams
+            .entry(&line.labels)
+            .or_default()
+            .push([nanos, line.line.clone()]);
+    }
+    let streams: Vec<Value> = streams
+        .into_iter()
+        .map(|(labels, values)| json!({ "stream": labels, "values": values }))
+        .collect();
+    json!({ "streams": streams })
+}
+
+pub struct LokiPusher {
+    receiver: mpsc::Receiver<LokiLine>,
+    dropped: Arc<AtomicUsize>,
+    push_url: Url,
+    client: reqwest::Client,
+}
+
+impl LokiPusher {
+    pub async fn run(mut self) {
+        let mut batch = Vec::new();
+        let mut backoff = Duration::ZERO;
+        let mut retry_at = Instant::now();
+        let mut dropped = 0;
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                line = self.receiver.recv() => match line {
+                    Some(line) => {
+                        batch.push(line);
+                        if batch.len() < BATCH_SIZE {
+                            continue;
+                        }
+                    }
+                    None => break,
+                },
+                _ = flush.tick() => {}
+            }
+            if batch.is_empty() || Instant::now() < retry_at {
+                // Loki is down, so only keep the most recent lines
+                if batch.len() > QUEUE_SIZE {
+                    let excess = batch.len() - QUEUE_SIZE;
+                    batch.drain(..excess);
+                    dropped += excess;
+                }
+                continue;
+            }
+            match self.push(&batch).await {
+                Ok(()) => {
+                    dropped += self.dropped.swap(0, Ordering::Relaxed);
+                    if dropped > 0 {
+                        log::warn!(
+                            "Dropped {} test log lines while Loki was unreachable",
+                            dropped
+                        );
+                        dropped = 0;
+                    }
+                    batch.clear();
+                    backoff = Duration::ZERO;
+                }
+                Err(err) => {
+                    backoff = (backoff * 2).max(Duration::from_secs(1)).min(MAX_BACKOFF);
+                    retry_at = Instant::now() + backoff;
+                    log::warn!(
+                        "Unable to ship test logs to Loki, retrying in {:?}: {:?}",
+                        backoff,
+                        err
+                    );
+                }
+            }
+        }
+        if !batch.is_empty() {
+            if let Err(err) = self.push(&batch).await {
+                log::warn!("Dropped {} test log lines: {:?}", batch.len(), err);
+            }
+        }
+    }
+    async fn push(&self, lines: &[LokiLine]) -> anyhow::Result<()> {
+        self.client
+            .post(self.push_url.clone())
+            .timeout(Duration::from_secs(10))
+            .json(&push_body(lines))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+// Accepts "host:port" as well as a full url
+pub fn parse_url(addr: &str) -> anyhow::Result<Url> {
+    if addr.contains("://") {
+        Ok(Url::parse(addr)?)
+    } else {
+        Ok(Url::parse(&format!("http://{}", addr))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_push_body_groups_streams() {
+        let (shipper, mut pusher) = LokiShipper::new(
+            parse_url("loki.localhost:3100").unwrap(),
+            Labels::from([("host".into(), "helicon".into())]),
+        );
+        assert_eq!(
+            pusher.push_url.as_str(),
+            "http://loki.localhost:3100/loki/api/v1/push"
+        );
+        let stream = shipper.stream(Labels::from([("run_id".into(), "abc".into())]));
+        let at = chrono::Utc.timestamp_opt(1, 0).unwrap();
+        stream.send(false, "one".into(), at);
+        stream.send(true, "oops".into(), at);
+        stream.set_label("stage", "Runnable");
+        stream.send(false, "two".into(), at);
+        stream.send(
+            false,
+            "too late".into(),
+            chrono::DateTime::<chrono::Utc>::MAX_UTC,
+        );
+
+        let mut lines = Vec::new();
+        while let Ok(line) = pusher.receiver.try_recv() {
+            lines.push(line);
+        }
+        let body = push_body(&lines);
+        let streams = body["streams"].as_array().unwrap();
+        assert_eq!(streams.len(), 3);
+        let stderr = streams
+            .iter()
+            .find(|stream| stream["stream"]["stream"] == "stderr")
+            .unwrap();
+        assert_eq!(
+            stderr["stream"],
+            json!({ "host": "helicon", "run_id": "abc", "stream": "stderr" })
+        );
+        assert_eq!(stderr["values"], json!([["1000000000", "oops"]]));
+        assert!(streams.iter().any(
+            |stream| stream["stream"]["stage"] == "Runnable" && stream["values"][0][1] == "two"
+        ));
+        assert!(!body.to_string().contains("too late"));
+    }
+}
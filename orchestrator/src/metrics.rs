@@ -0,0 +1,277 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use prometheus::{
+    exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    resource_ledger::{ResourceLedger, SharedLedger},
+    running_test_map::RunningTestMap,
+};
+
+// Everything the orchestrator exports to Prometheus.
+//
+// Counters and histograms are updated as tests move through the stages.
+// Queue depth and ledger occupancy are read from the RunningTestMap and
+// the ledger whenever /metrics is scraped, so they can never drift.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    tests_in_stage: IntGaugeVec,
+    stage_duration: HistogramVec,
+    test_outcomes: IntCounterVec,
+    ledger_holders: IntGaugeVec,
+    ledger_reclaims: IntCounterVec,
+    dependency_build_duration: HistogramVec,
+    stats_sink_errors: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("htp".into()), None)?;
+        // From a second up to about 4.5 hours
+        let duration_buckets = exponential_buckets(1.0, 2.0, 15)?;
+        let tests_in_stage = IntGaugeVec::new(
+            Opts::new("tests_in_stage", "Tests currently in each stage"),
+            &["stage"],
+        )?;
+        let stage_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "stage_duration_seconds",
+                "Time tests spent in a stage, including time spent waiting for it",
+            )
+            .buckets(duration_buckets.clone()),
+            &["stage"],
+        )?;
+        let test_outcomes = IntCounterVec::new(
+            Opts::new("test_outcomes_total", "Tests that have terminated"),
+            &["test_group", "test_name", "device_type", "outcome"],
+        )?;
+        let ledger_holders = IntGaugeVec::new(
+            Opts::new(
+                "ledger_holders",
+                "Tests holding a lock on each device and apparatus",
+            ),
+            &["kind", "resource"],
+        )?;
+        let ledger_reclaims = IntCounterVec::new(
+            Opts::new(
+                "ledger_reclaims_total",
+                "Locks taken from tests because their lease expired or an admin forced it",
+            ),
+            &["kind", "resource", "reason"],
+        )?;
+        let dependency_build_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "dependency_build_duration_seconds",
+                "Time spent building dependencies",
+            )
+            .buckets(duration_buckets),
+            &["dependency", "result"],
+        )?;
+        let stats_sink_errors = IntCounterVec::new(
+            Opts::new(
+                "stats_sink_errors_total",
+                "Documents the statistics backend failed to store",
+            ),
+            &["index"],
+        )?;
+        registry.register(Box::new(tests_in_stage.clone()))?;
+        registry.register(Box::new(stage_duration.clone()))?;
+        registry.register(Box::new(test_outcomes.clone()))?;
+        registry.register(Box::new(ledger_holders.clone()))?;
+        registry.register(Box::new(ledger_reclaims.clone()))?;
+        registry.register(Box::new(dependency_build_duration.clone()))?;
+        registry.register(Box::new(stats_sink_errors.clone()))?;
+        Ok(Self {
+            registry,
+            tests_in_stage,
+            stage_duration,
+            test_outcomes,
+            ledger_holders,
+            ledger_reclaims,
+            dependency_build_duration,
+            stats_sink_errors,
+        })
+    }
+    pub fn stage_finished(&self, stage: &str, spent: Duration) {
+        self.stage_duration
+            .with_label_values(&[stage])
+            .observe(spent.as_secs_f64());
+    }
+    pub fn test_terminated(
+        &self,
+        test_group: &str,
+        test_name: &str,
+        device_type: &str,
+        outcome: &str,
+    ) {
+        self.test_outcomes
+            .with_label_values(&[test_group, test_name, device_type, outcome])
+            .inc();
+    }
+    // `reason` is lease_expired or forced
+    pub fn resource_reclaimed(&self, kind: &str, resource: &str, reason: &str) {
+        self.ledger_reclaims
+            .with_label_values(&[kind, resource, reason])
+            .inc();
+    }
+    pub fn dependency_built(&self, dependency: &str, succeeded: bool, spent: Duration) {
+        let result = if succeeded { "success" } else { "failure" };
+        self.dependency_build_duration
+            .with_label_values(&[dependency, result])
+            .observe(spent.as_secs_f64());
+    }
+    pub fn stats_sink_error(&self, index: &str) {
+        self.stats_sink_errors.with_label_values(&[index]).inc();
+    }
+    // The text exposition format, with the gauges brought up to date first
+    pub fn render(
+        &self,
+        test_map: &Mutex<RunningTestMap>,
+        ledger: &SharedLedger,
+    ) -> anyhow::Result<String> {
+        {
+            let map = test_map.lock().unwrap();
+            self.tests_in_stage.reset();
+            for entry in &map.map {
+                self.tests_in_stage.with_label_values(&[&entry.stage]).inc();
+            }
+        }
+        {
+            let ledger = ledger.lock();
+            self.ledger_holders.reset();
+            self.set_holders("device", &ledger.devices);
+            self.set_holders("apparatus", &ledger.apparatuses);
+        }
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+    fn set_holders(&self, kind: &str, ledger: &ResourceLedger) {
+        for (resource, holders) in ledger.occupancy() {
+            self.ledger_holders
+                .with_label_values(&[kind, &resource])
+                .set(holders as i64);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    metrics: Arc<Metrics>,
+    test_map: Arc<Mutex<RunningTestMap>>,
+    ledger: Arc<SharedLedger>,
+}
+
+// Serves /metrics on `addr` until `shutdown` fires
+pub async fn serve(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    test_map: Arc<Mutex<RunningTestMap>>,
+    ledger: Arc<SharedLedger>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(MetricsState {
+            metrics,
+            test_map,
+            ledger,
+        });
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    axum::Server::try_bind(&addr)
+        .with_context(|| format!("Binding the metrics endpoint to {}", addr))?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+    Ok(())
+}
+
+async fn scrape(State(state): State<MetricsState>) -> impl IntoResponse {
+    match state.metrics.render(&state.test_map, &state.ledger) {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(err) => {
+            log::error!("Unable to render metrics: {:?}", err);
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        resource_ledger::LabLedger,
+        resources::{ApparatusLock, ResourceClaim},
+        running_test_map::RunningTestMapEntry,
+    };
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new().unwrap();
+        let test_map = Arc::new(Mutex::new(RunningTestMap::default()));
+        for (id, stage) in [("a", "Queued"), ("b", "Queued"), ("c", "Runnable")] {
+            test_map.lock().unwrap().insert(
+                2,
+                RunningTestMapEntry {
+                    id: ("general".into(), "simpleconn".into()),
+                    ver: id.into(),
+                    stage: stage.into(),
+                    entry_time: std::time::SystemTime::now(),
+                    cancel: CancellationToken::new(),
+                    cancel_requested: false,
+                },
+            );
+        }
+        let ledger = SharedLedger::new(LabLedger::default());
+        ledger
+            .lock()
+            .acquire_claim(
+                "c",
+                &ResourceClaim {
+                    device: "pi4".into(),
+                    apparatuses: vec![ApparatusLock {
+                        name: "camera".into(),
+                        exclusive: false,
+                        wrapped_by: None,
+                    }],
+                },
+            )
+            .unwrap();
+
+        metrics.stage_finished("Validated", Duration::from_secs(3));
+        metrics.test_terminated("general", "simpleconn", "linux", "passed");
+        metrics.dependency_built("viam_server_appimage", true, Duration::from_secs(90));
+        metrics.stats_sink_error("logs");
+        metrics.resource_reclaimed("device", "pi3", "lease_expired");
+
+        let text = metrics.render(&test_map, &ledger).unwrap();
+        for line in [
+            "htp_tests_in_stage{stage=\"Queued\"} 2",
+            "htp_tests_in_stage{stage=\"Runnable\"} 1",
+            "htp_stage_duration_seconds_count{stage=\"Validated\"} 1",
+            "htp_test_outcomes_total{device_type=\"linux\",outcome=\"passed\",test_group=\"general\",test_name=\"simpleconn\"} 1",
+            "htp_ledger_holders{kind=\"device\",resource=\"pi4\"} 1",
+            "htp_ledger_holders{kind=\"apparatus\",resource=\"camera\"} 1",
+            "htp_dependency_build_duration_seconds_sum{dependency=\"viam_server_appimage\",result=\"success\"} 90",
+            "htp_stats_sink_errors_total{index=\"logs\"} 1",
+            "htp_ledger_reclaims_total{kind=\"device\",reason=\"lease_expired\",resource=\"pi3\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} missing from\n{}", line, text);
+        }
+
+        // Released resources and finished tests drop out on the next scrape
+        ledger.release_all("c");
+        test_map.lock().unwrap().remove("c");
+        let text = metrics.render(&test_map, &ledger).unwrap();
+        assert!(!text.contains("Runnable"));
+        assert!(!text.contains("pi4"));
+    }
+}
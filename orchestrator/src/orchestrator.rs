@@ -1,174 +1,465 @@
 use std::{
+    collections::BTreeMap,
     path::PathBuf,
-    sync::{mpsc::Receiver, Arc, Mutex},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use anyhow::Context;
-use crossbeam::channel::Sender;
-use tokio::{runtime::Runtime, task::JoinHandle};
+use anyhow::{anyhow, Context};
+use tokio::{
+    runtime::Runtime,
+    signal::unix::{signal, SignalKind},
+    sync::{
+        mpsc::{self, UnboundedSender},
+        Notify,
+    },
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
+    api,
     config::{
-        self,
         orchestrator_config::{self, OrchestratorConfig},
-        tests::TestMap,
+        tests::TestSpecificationID,
         Config,
     },
-    htp_test::{HtpTest, Queued, Validated, PRIORITY_ADMIN},
+    environment::docker_env::DockerEnvironment,
+    htp_test::{
+        new_test_id, HtpTest, Queued, Terminated, TerminationReason, TestID, TestPriority,
+        PRIORITY_ADMIN,
+    },
+    inventory::Inventory,
+    journal::{Journal, JournaledTest},
+    logging,
+    loki::{Labels, LokiShipper},
+    metrics::{self, Metrics},
+    priority_queue::priority_channel,
+    resource_ledger::{LabLedger, SharedLedger, SystemClock},
     running_test_map::RunningTestMap,
     stages::{
         aquiring::Aquirer, preperation::Preparer, running::Runner, termination::TerminatedSink,
         validation::Validator,
     },
+    statistics::{DbWrapper, WrapperType},
+    stats_sink::{self, StatsSink},
 };
 
 pub struct Orchestrator {
-    // validator: Validator,
-    test_map: Arc<Mutex<RunningTestMap>>,
-    main_input: Sender<HtpTest<Queued>>,
+    handle: OrchestratorHandle,
+    terminated_input: UnboundedSender<HtpTest<Terminated>>,
+    // Tests the previous orchestrator never finished
+    unfinished: Vec<JournaledTest>,
     validator_handle: JoinHandle<anyhow::Result<()>>,
     preparer_handle: JoinHandle<anyhow::Result<()>>,
     aquirer_handle: JoinHandle<anyhow::Result<()>>,
     runner_handle: JoinHandle<anyhow::Result<()>>,
     terminated_sink_handle: JoinHandle<anyhow::Result<()>>,
     runtime: Runtime,
-    close_sender: Sender<()>,
+}
+
+// Creates, submits and cancels tests.
+// Cheap to clone, so the API gets its own copy.
+#[derive(Clone)]
+pub struct OrchestratorHandle {
+    config_path: PathBuf,
+    orchestrator_config: OrchestratorConfig,
+    test_map: Arc<Mutex<RunningTestMap>>,
+    stats_backend: Arc<dyn StatsSink>,
+    metrics: Arc<Metrics>,
+    // None if test output is not shipped to Loki
+    loki: Option<LokiShipper>,
+    main_input: UnboundedSender<HtpTest<Queued>>,
+    test_cancelled: Arc<Notify>,
+    ledger: Arc<SharedLedger>,
+    inventory: Arc<Inventory>,
+    shutdown: CancellationToken,
 }
 
 impl Orchestrator {
-    pub fn new() -> Self {
-        let (main_input, valid_receiver) = crossbeam::channel::unbounded();
-        let (valid_sender, prepare_receiver) = crossbeam::channel::unbounded();
-        let (prepare_sender, aquire_receiver) = crossbeam::channel::unbounded();
-        let (aquire_sender, run_receiver) = crossbeam::channel::unbounded();
+    pub fn new(config_path: PathBuf) -> anyhow::Result<Self> {
+        let orchestrator_config =
+            orchestrator_config::parse(&config_path.join("orchestrator.json5"))
+                .context("Orchestrator parsing")?;
+        Self::with_config(config_path, orchestrator_config)
+    }
+    // For when orchestrator.json5 has already been read, and maybe overridden
+    pub fn with_config(
+        config_path: PathBuf,
+        orchestrator_config: OrchestratorConfig,
+    ) -> anyhow::Result<Self> {
+        let runtime = Runtime::new()?;
+        let metrics = Arc::new(Metrics::new().context("Creating metrics")?);
+        let (loki, stats_backend) = {
+            let _runtime = runtime.enter();
+            let loki = logging::init(&orchestrator_config);
+            log::info!(
+                "Config folder {:?}, effective orchestrator config: {}",
+                config_path,
+                serde_json::to_string(&orchestrator_config)?
+            );
+            // Tests are validated against the config when they are submitted,
+            // but a broken config is better found out about now
+            if let Err(err) = Config::new(&config_path) {
+                log::error!("{:#}", err);
+            }
+            let stats_backend = stats_sink::from_config(&orchestrator_config, Arc::clone(&metrics))
+                .context("Creating the stats backend")?;
+            (loki, stats_backend)
+        };
+        let concurrency = orchestrator_config.stage_concurrency.clone();
+        let (journal, replay) =
+            Journal::open(&orchestrator_config.htp_folder_root.join("journal.jsonl"))
+                .context("Opening the journal")?;
 
-        let (terminated_sender, terminated_receiver) = crossbeam::channel::unbounded();
-        let (close_sender, close_receiver) = crossbeam::channel::unbounded();
+        let (main_input, valid_receiver) = mpsc::unbounded_channel();
+        let (valid_sender, prepare_receiver) = mpsc::unbounded_channel();
+        // Tests compete for devices and for runner slots,
+        // so those two stages take the most important test first
+        let aging_interval = Duration::from_secs(orchestrator_config.priority_aging_sec);
+        let (prepare_sender, aquire_receiver) = priority_channel(aging_interval);
+        let (aquire_sender, run_receiver) = priority_channel(aging_interval);
 
-        let mut validator = Validator::new(
+        let (terminated_sender, terminated_receiver) = mpsc::unbounded_channel();
+        let ledger = Arc::new(
+            SharedLedger::new(LabLedger::with_clock(
+                Arc::new(SystemClock),
+                Duration::from_secs(orchestrator_config.lease_ttl_sec.max(1)),
+            ))
+            .with_metrics(Arc::clone(&metrics)),
+        );
+        let shutdown = CancellationToken::new();
+        let test_map = Arc::new(Mutex::new(RunningTestMap::with_journal(
+            journal.spawn_writer()?,
+        )));
+        let test_cancelled = Arc::new(Notify::new());
+
+        let validator = Validator::new(
             valid_receiver,
-            valid_sender.clone(),
+            valid_sender,
             terminated_sender.clone(),
+            concurrency.validation,
         );
 
-        let mut preparer = Preparer::new(
+        let preparer = Preparer::new(
             prepare_receiver,
-            prepare_sender.clone(),
+            prepare_sender,
             terminated_sender.clone(),
+            concurrency.preperation,
         );
 
-        let mut aquirer = Aquirer::new(
+        let aquirer = Aquirer::new(
             aquire_receiver,
-            aquire_sender.clone(),
+            aquire_sender,
             terminated_sender.clone(),
+            Arc::clone(&ledger),
+            shutdown.clone(),
+            Arc::clone(&test_cancelled),
+        );
+        let runner = Runner::new(
+            run_receiver,
+            terminated_sender.clone(),
+            terminated_sender.clone(),
+            concurrency.running,
+        );
+        let terminated_sink = TerminatedSink::new(
+            terminated_receiver,
+            Arc::clone(&ledger),
+            concurrency.termination,
         );
-        let mut runner = Runner::new(run_receiver, terminated_sender.clone(), terminated_sender);
-        let mut terminated_sink = TerminatedSink::new(terminated_receiver);
-
-        let runtime = Runtime::new().unwrap();
 
         let handle = runtime.handle();
 
-        let close_receiver_inst = close_receiver.clone();
-        let validator_handle = handle.spawn(async move {
-            loop {
-                if close_receiver_inst.try_recv().is_ok() {
-                    return validator.close();
+        // Each stage wakes up as soon as a test arrives on its input
+        // and exits once every stage upstream of it has exited.
+        let validator_handle = handle.spawn(validator.run());
+        let preparer_handle = handle.spawn(preparer.run());
+        let aquirer_handle = handle.spawn(aquirer.run());
+        let runner_handle = handle.spawn(runner.run());
+        let terminated_sink_handle = handle.spawn(terminated_sink.run());
+        if !orchestrator_config.metrics_addr.is_empty() {
+            let addr = orchestrator_config
+                .metrics_addr
+                .parse()
+                .context("Parsing metrics_addr")?;
+            let serve = metrics::serve(
+                addr,
+                Arc::clone(&metrics),
+                Arc::clone(&test_map),
+                Arc::clone(&ledger),
+                shutdown.clone(),
+            );
+            handle.spawn(async move {
+                if let Err(err) = serve.await {
+                    log::error!("Metrics endpoint stopped: {:?}", err);
                 }
-                tokio::time::sleep(validator.desired_poll_delay()).await;
-                validator.process_one()?;
-            }
-        });
-        let close_receiver_inst = close_receiver.clone();
-        let preparer_handle = handle.spawn(async move {
-            loop {
-                if close_receiver_inst.try_recv().is_ok() {
-                    return preparer.close();
-                }
-                tokio::time::sleep(preparer.desired_poll_delay()).await;
-                preparer.process_one().await?;
-            }
-        });
-        let close_receiver_inst = close_receiver.clone();
-        let aquirer_handle = handle.spawn(async move {
-            loop {
-                if close_receiver_inst.try_recv().is_ok() {
-                    return aquirer.close();
-                }
-                tokio::time::sleep(aquirer.desired_poll_delay()).await;
-                aquirer.process_one()?;
-            }
-        });
-        let close_receiver_inst = close_receiver.clone();
-        let runner_handle = handle.spawn(async move {
-            loop {
-                if close_receiver_inst.try_recv().is_ok() {
-                    return runner.close();
-                }
-                tokio::time::sleep(runner.desired_poll_delay()).await;
-                runner.process_one().await?;
-            }
-        });
-        let close_receiver_inst = close_receiver.clone();
-        let terminated_sink_handle = handle.spawn(async move {
-            loop {
-                if close_receiver_inst.try_recv().is_ok() {
-                    return terminated_sink.close();
-                }
-                tokio::time::sleep(terminated_sink.desired_poll_delay()).await;
-                terminated_sink.process_one()?;
-            }
-        });
-        Self {
+            });
+        }
+        let orchestrator_handle = OrchestratorHandle {
+            inventory: Arc::new(Inventory::new(&config_path)),
+            config_path,
+            orchestrator_config,
+            test_map,
+            stats_backend,
+            metrics,
+            loki,
             main_input,
-            test_map: Arc::new(Mutex::new(RunningTestMap::default())),
+            test_cancelled,
+            ledger,
+            shutdown,
+        };
+        if !orchestrator_handle.orchestrator_config.api_addr.is_empty() {
+            let addr = orchestrator_handle
+                .orchestrator_config
+                .api_addr
+                .parse()
+                .context("Parsing api_addr")?;
+            let serve = api::serve(addr, orchestrator_handle.clone());
+            handle.spawn(async move {
+                if let Err(err) = serve.await {
+                    log::error!("API stopped: {:?}", err);
+                }
+            });
+        }
+        Ok(Self {
+            handle: orchestrator_handle,
+            terminated_input: terminated_sender,
+            unfinished: replay.unfinished,
             runtime,
             validator_handle,
             preparer_handle,
             aquirer_handle,
             runner_handle,
             terminated_sink_handle,
-            close_sender,
-        }
+        })
+    }
+    pub fn handle(&self) -> &OrchestratorHandle {
+        &self.handle
     }
     pub fn start(&mut self) -> anyhow::Result<()> {
-        let config_path = PathBuf::from("../config");
-        let orchestrator_config =
-            orchestrator_config::parse(&config_path.join("orchestrator.json5"))
-                .context("Orchestrator parsing")?;
-        std::fs::remove_dir_all(&orchestrator_config.htp_folder_root)?;
-        let test_spec_id = ("general".into(), "simpleconn".into());
-        let priority = PRIORITY_ADMIN;
-        let test = HtpTest::<Queued>::new(
-            &config_path,
-            orchestrator_config,
-            test_spec_id,
-            priority,
-            Arc::clone(&self.test_map),
-        );
-        self.main_input.send(test?)?;
+        // Tests only come in through the API or from the journal
+        self.recover()
+    }
+    pub fn submit(
+        &self,
+        test_spec_id: TestSpecificationID,
+        priority: TestPriority,
+    ) -> anyhow::Result<TestID> {
+        self.handle.submit(test_spec_id, priority)
+    }
+    // Picks up the tests that the last orchestrator left unfinished.
+    //
+    // Tests that had not reached the Aquirer yet are simply queued again.
+    // Anything later may have been running, and we cannot know what state it
+    // left its environment in, so it is terminated as crashed instead.
+    // Containers left behind by either are removed. Resources need no cleanup
+    // because the ledger only lives in memory.
+    fn recover(&mut self) -> anyhow::Result<()> {
+        for journaled in std::mem::take(&mut self.unfinished) {
+            log::info!(
+                "Recovering test {} {:?} which was left in stage {}",
+                journaled.id,
+                journaled.test_spec_id,
+                journaled.stage
+            );
+            match self
+                .runtime
+                .block_on(DockerEnvironment::remove_test_containers(&journaled.id))
+            {
+                Ok(0) => {}
+                Ok(removed) => log::info!(
+                    "Removed {} containers left behind by test {}",
+                    removed,
+                    journaled.id
+                ),
+                Err(err) => log::warn!(
+                    "Unable to clean up containers for test {}: {:?}",
+                    journaled.id,
+                    err
+                ),
+            }
+            let priority = TestPriority::from_level(journaled.priority).unwrap_or_else(|| {
+                log::warn!(
+                    "Unknown priority {} for test {}",
+                    journaled.priority,
+                    journaled.id
+                );
+                PRIORITY_ADMIN
+            });
+            let test = self.handle.new_test(
+                journaled.id.clone(),
+                journaled.test_spec_id.clone(),
+                priority,
+            )?;
+            if journaled.may_have_been_running() {
+                self.terminated_input
+                    .send(test.terminate(TerminationReason::OrchestratorCrashed))?;
+            } else {
+                self.handle.main_input.send(test)?;
+            }
+        }
         Ok(())
     }
     pub fn is_finished(&self) -> bool {
-        let mut map = self.test_map.lock().unwrap();
-        println!("{:#?}", &map);
+        let map = self.handle.test_map.lock().unwrap();
+        if !map.map.is_empty() {
+            let mut per_stage = BTreeMap::new();
+            for entry in &map.map {
+                *per_stage.entry(entry.stage.as_str()).or_insert(0) += 1;
+            }
+            log::debug!("Waiting on tests: {:?}", per_stage);
+        }
         map.map.is_empty()
     }
+    // Blocks until SIGINT or SIGTERM is received or, unless the API
+    // is there to submit more, until every submitted test has terminated
+    pub fn wait_for_shutdown(&self) -> anyhow::Result<()> {
+        let serving_api = !self.handle.orchestrator_config.api_addr.is_empty();
+        self.runtime.block_on(async {
+            let mut sigterm = signal(SignalKind::terminate())?;
+            let mut poll = tokio::time::interval(Duration::from_millis(5000));
+            loop {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {
+                        log::info!("Received SIGINT");
+                        return Ok(());
+                    }
+                    _ = sigterm.recv() => {
+                        log::info!("Received SIGTERM");
+                        return Ok(());
+                    }
+                    _ = poll.tick(), if !serving_api => {
+                        if self.is_finished() {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        })
+    }
+    // Cancels every test and waits for the stages to drain.
+    //
+    // Tests that are still queued are terminated without running and in-flight
+    // tests are told to stop, tearing down their environments as they go.
+    // Either way they all pass through the TerminatedSink
+    // with an "orchestrator shutdown" reason.
     pub fn stop(self) -> anyhow::Result<()> {
-        self.close_sender.send(())?;
-        self.close_sender.send(())?;
-        self.close_sender.send(())?;
-        self.close_sender.send(())?;
-        self.close_sender.send(())?;
-        self.runtime
-            .block_on(async { self.validator_handle.await? })?;
-        self.runtime
-            .block_on(async { self.preparer_handle.await? })?;
-        self.runtime
-            .block_on(async { self.aquirer_handle.await? })?;
-        self.runtime.block_on(async { self.runner_handle.await? })?;
-        self.runtime
-            .block_on(async { self.terminated_sink_handle.await? })?;
-        Ok(())
+        log::info!("Shutting down");
+        self.handle.shutdown.cancel();
+        // Closing the main input closes every stage in turn.
+        // The API drops its copy once it sees the shutdown.
+        drop(self.handle.main_input);
+        drop(self.terminated_input);
+        let drain_timeout =
+            Duration::from_secs(self.handle.orchestrator_config.shutdown_drain_timeout_sec);
+        let drained = self
+            .runtime
+            .block_on(tokio::time::timeout(drain_timeout, async {
+                self.validator_handle.await??;
+                self.preparer_handle.await??;
+                self.aquirer_handle.await??;
+                self.runner_handle.await??;
+                self.terminated_sink_handle.await??;
+                anyhow::Ok(())
+            }));
+        // Statistics about the drained tests would otherwise be lost
+        if self
+            .runtime
+            .block_on(tokio::time::timeout(
+                drain_timeout,
+                self.handle.stats_backend.flush(),
+            ))
+            .is_err()
+        {
+            log::error!("Timed out flushing statistics");
+        }
+        // Waits for the journal writer to record how the drained tests ended
+        let journal = self.handle.test_map.lock().unwrap().journal.take();
+        drop(journal);
+        match drained {
+            Ok(result) => result,
+            Err(_) => {
+                let map = self.handle.test_map.lock().unwrap();
+                log::error!(
+                    "Tests were still in flight after waiting {:?} for them to drain: {:#?}",
+                    drain_timeout,
+                    &map
+                );
+                Err(anyhow!("Timed out draining in-flight tests"))
+            }
+        }
+    }
+}
+
+impl OrchestratorHandle {
+    pub fn submit(
+        &self,
+        test_spec_id: TestSpecificationID,
+        priority: TestPriority,
+    ) -> anyhow::Result<TestID> {
+        let test_id = new_test_id();
+        let test = self.new_test(test_id.clone(), test_spec_id, priority)?;
+        self.main_input.send(test)?;
+        Ok(test_id)
+    }
+    fn new_test(
+        &self,
+        test_id: TestID,
+        test_spec_id: TestSpecificationID,
+        priority: TestPriority,
+    ) -> anyhow::Result<HtpTest<Queued>> {
+        let mut stats_sink = DbWrapper::new(
+            WrapperType::Test,
+            test_id.clone(),
+            Arc::clone(&self.stats_backend),
+        )
+        .with_metrics(Arc::clone(&self.metrics));
+        if let Some(loki) = &self.loki {
+            stats_sink = stats_sink.with_loki(loki.stream(Labels::from([
+                ("run_id".into(), test_id.clone()),
+                ("test_group".into(), test_spec_id.0.clone()),
+                ("test_name".into(), test_spec_id.1.clone()),
+                ("stage".into(), "queued".into()),
+            ])));
+        }
+        HtpTest::<Queued>::new(
+            &self.config_path,
+            self.orchestrator_config.clone(),
+            test_id,
+            test_spec_id,
+            priority,
+            Arc::clone(&self.test_map),
+            stats_sink,
+            self.shutdown.child_token(),
+        )
+    }
+    // Cancels a queued or running test, which is then terminated as cancelled.
+    // Returns the stage it was in, or None if there is no such test.
+    pub fn cancel(&self, test_id: &str) -> Option<String> {
+        let stage = self.test_map.lock().unwrap().cancel(test_id)?;
+        log::info!("Cancelling test {} in stage {}", test_id, stage);
+        // The Aquirer only looks for cancelled tests when woken up
+        self.test_cancelled.notify_one();
+        Some(stage)
+    }
+    // The lab as it is configured right now.
+    // Tests read their own copy when they are created.
+    pub fn lab_config(&self) -> anyhow::Result<Config> {
+        Config::new(&self.config_path)
+    }
+    pub fn ledger(&self) -> &SharedLedger {
+        &self.ledger
+    }
+    pub fn inventory(&self) -> &Inventory {
+        &self.inventory
+    }
+    pub fn test_map(&self) -> &Mutex<RunningTestMap> {
+        &self.test_map
+    }
+    pub fn stats_backend(&self) -> &dyn StatsSink {
+        self.stats_backend.as_ref()
+    }
+    pub fn shutdown(&self) -> &CancellationToken {
+        &self.shutdown
     }
 }
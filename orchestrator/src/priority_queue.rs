@@ -0,0 +1,296 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc::error::SendError, Notify};
+
+// Anything that can wait in a priority channel.
+// Lower levels are more important and are handed out first.
+pub trait Prioritized {
+    fn priority_level(&self) -> usize;
+}
+
+// A channel that hands out the most important item first
+// rather than the one that has been waiting the longest.
+//
+// To keep low priority work from being starved by a steady stream
+// of important work, items gain one priority level for every
+// `aging_interval` that they spend waiting.
+// Items with the same effective priority come out in the order they went in.
+pub fn priority_channel<T: Prioritized>(
+    aging_interval: Duration,
+) -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(QueueState::new(aging_interval)),
+        notify: Notify::new(),
+    });
+    (
+        PrioritySender {
+            shared: Arc::clone(&shared),
+        },
+        PriorityReceiver { shared },
+    )
+}
+
+struct Shared<T> {
+    state: Mutex<QueueState<T>>,
+    notify: Notify,
+}
+
+pub struct PrioritySender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Prioritized> PrioritySender<T> {
+    pub fn send(&self, item: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receiver_closed {
+            return Err(SendError(item));
+        }
+        state.push(item, Instant::now());
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for PrioritySender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for PrioritySender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            // Wake the receiver so that it notices the channel has closed
+            self.shared.notify.notify_one();
+        }
+    }
+}
+
+pub struct PriorityReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Prioritized> PriorityReceiver<T> {
+    // Waits for the most important item.
+    // Returns None once every sender is gone and nothing is left waiting.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(item) = state.pop(Instant::now()) {
+                    return Some(item);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            // notify_one() stores a permit if nobody is waiting yet,
+            // so a send between the unlock and here is not missed
+            self.shared.notify.notified().await;
+        }
+    }
+    // Takes the most important item for which `f` returns Some,
+    // leaving everything else waiting.
+    pub fn take_where<R>(&mut self, f: impl FnMut(&T) -> Option<R>) -> Option<(T, R)> {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .take_where(Instant::now(), f)
+    }
+    // Waits until something is sent or the last sender goes away
+    pub async fn changed(&mut self) {
+        self.shared.notify.notified().await;
+    }
+    pub fn is_empty(&self) -> bool {
+        self.shared.state.lock().unwrap().waiting.is_empty()
+    }
+    // True once every sender is gone. There may still be items waiting.
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().senders == 0
+    }
+}
+
+impl<T> Drop for PriorityReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_closed = true;
+    }
+}
+
+struct Waiting<T> {
+    item: T,
+    level: usize,
+    enqueued_at: Instant,
+    seq: u64,
+}
+
+struct QueueState<T> {
+    waiting: Vec<Waiting<T>>,
+    aging_interval: Duration,
+    next_seq: u64,
+    senders: usize,
+    receiver_closed: bool,
+}
+
+impl<T: Prioritized> QueueState<T> {
+    fn new(aging_interval: Duration) -> Self {
+        Self {
+            waiting: Vec::new(),
+            aging_interval,
+            next_seq: 0,
+            senders: 1,
+            receiver_closed: false,
+        }
+    }
+    fn push(&mut self, item: T, now: Instant) {
+        self.waiting.push(Waiting {
+            level: item.priority_level(),
+            item,
+            enqueued_at: now,
+            seq: self.next_seq,
+        });
+        self.next_seq += 1;
+    }
+    fn effective_level(&self, waiting: &Waiting<T>, now: Instant) -> usize {
+        if self.aging_interval.is_zero() {
+            return waiting.level;
+        }
+        let waited = now.saturating_duration_since(waiting.enqueued_at);
+        let levels_gained = waited.as_secs_f64() / self.aging_interval.as_secs_f64();
+        waiting.level.saturating_sub(levels_gained as usize)
+    }
+    fn pop(&mut self, now: Instant) -> Option<T> {
+        self.take_where(now, |_| Some(())).map(|(item, _)| item)
+    }
+    fn take_where<R>(
+        &mut self,
+        now: Instant,
+        mut f: impl FnMut(&T) -> Option<R>,
+    ) -> Option<(T, R)> {
+        let mut order: Vec<usize> = (0..self.waiting.len()).collect();
+        order.sort_by_key(|idx| {
+            let waiting = &self.waiting[*idx];
+            (self.effective_level(waiting, now), waiting.seq)
+        });
+        for idx in order {
+            if let Some(result) = f(&self.waiting[idx].item) {
+                return Some((self.waiting.remove(idx).item, result));
+            }
+        }
+        None
+    }
+}
+
+// Lets a stage worker pull from either a plain channel or a priority channel
+pub trait StageInput<T> {
+    fn recv(&mut self) -> impl Future<Output = Option<T>> + Send;
+}
+
+impl<T: Send> StageInput<T> for tokio::sync::mpsc::UnboundedReceiver<T> {
+    fn recv(&mut self) -> impl Future<Output = Option<T>> + Send {
+        tokio::sync::mpsc::UnboundedReceiver::recv(self)
+    }
+}
+
+impl<T: Prioritized + Send> StageInput<T> for PriorityReceiver<T> {
+    fn recv(&mut self) -> impl Future<Output = Option<T>> + Send {
+        PriorityReceiver::recv(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Item(&'static str, usize);
+    impl Prioritized for Item {
+        fn priority_level(&self) -> usize {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_pop_by_priority_then_order() {
+        let now = Instant::now();
+        let mut state = QueueState::new(Duration::ZERO);
+        state.push(Item("canary", 4), now);
+        state.push(Item("manual-1", 2), now);
+        state.push(Item("oneshot", 1), now);
+        state.push(Item("manual-2", 2), now);
+
+        assert_eq!(state.pop(now).unwrap().0, "oneshot");
+        assert_eq!(state.pop(now).unwrap().0, "manual-1");
+        assert_eq!(state.pop(now).unwrap().0, "manual-2");
+        assert_eq!(state.pop(now).unwrap().0, "canary");
+        assert!(state.pop(now).is_none());
+    }
+
+    #[test]
+    fn test_aging_prevents_starvation() {
+        let start = Instant::now();
+        let mut state = QueueState::new(Duration::from_secs(60));
+        state.push(Item("canary", 4), start);
+
+        // Three levels of aging brings the canary run level with a fresh manual run.
+        // It then wins because it has been waiting longer.
+        let later = start + Duration::from_secs(3 * 60);
+        state.push(Item("manual", 1), later);
+        assert_eq!(state.pop(later).unwrap().0, "canary");
+        assert_eq!(state.pop(later).unwrap().0, "manual");
+    }
+
+    #[test]
+    fn test_no_aging_before_interval() {
+        let start = Instant::now();
+        let mut state = QueueState::new(Duration::from_secs(60));
+        state.push(Item("canary", 4), start);
+        let later = start + Duration::from_secs(59);
+        state.push(Item("manual", 3), later);
+        assert_eq!(state.pop(later).unwrap().0, "manual");
+    }
+
+    #[test]
+    fn test_take_where_skips_unsuitable() {
+        let now = Instant::now();
+        let mut state = QueueState::new(Duration::ZERO);
+        state.push(Item("needs-lidar", 0), now);
+        state.push(Item("software-only", 4), now);
+
+        let taken = state.take_where(now, |item| (item.0 != "needs-lidar").then_some(item.1));
+        assert_eq!(taken, Some((Item("software-only", 4), 4)));
+        assert_eq!(state.waiting.len(), 1);
+        assert!(state.take_where(now, |_| None::<()>).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_channel_closes_after_draining() {
+        let (sender, mut receiver) = priority_channel(Duration::ZERO);
+        sender.send(Item("low", 3)).unwrap();
+        sender.send(Item("high", 0)).unwrap();
+        drop(sender);
+        assert_eq!(receiver.recv().await.unwrap().0, "high");
+        assert_eq!(receiver.recv().await.unwrap().0, "low");
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recv_wakes_on_send() {
+        let (sender, mut receiver) = priority_channel(Duration::ZERO);
+        let waiter = tokio::spawn(async move { receiver.recv().await });
+        tokio::task::yield_now().await;
+        sender.send(Item("late", 2)).unwrap();
+        assert_eq!(waiter.await.unwrap().unwrap().0, "late");
+    }
+}
@@ -1,26 +1,79 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
 use anyhow::anyhow;
+use tokio::{sync::Notify, task::JoinHandle};
 
-use crate::htp_test::TestID;
+use crate::{htp_test::TestID, metrics::Metrics, resources::ResourceClaim};
 
 // The ResourceLedger is a very interesting data structure
 // because it is designed to allow communication between tests
 // without trying too hard to prevent collisions.
 // It is more of a publish-first, ask-never kind of architecture
 // that is very amenable to greedy resource allocation.
-#[derive(Default, Debug, Clone)]
+//
+// Every lock is a lease that runs out after `lease_ttl` unless its test renews it,
+// so that resources held by a test that died without terminating are not lost forever.
+#[derive(Debug, Clone)]
 pub struct ResourceLedger {
     // This could be done with a HashMap
     // but it would be more complex and probably slower
-    //
-    // testid (usize), resourceid, is_exclusively_locked
-    resources: Vec<(TestID, String, bool)>,
+    resources: Vec<LedgerEntry>,
+    clock: Arc<dyn Clock>,
+    lease_ttl: Duration,
+}
+
+pub const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(300);
+
+impl Default for ResourceLedger {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock), DEFAULT_LEASE_TTL)
+    }
+}
+
+// Lets tests control time without sleeping
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerEntry {
+    pub testid: TestID,
+    pub resource: String,
+    pub exclusive: bool,
+    // The wrapper apparatus that this resource was locked as a part of, if any
+    pub wrapped_by: Option<String>,
+    pub expires_at: Instant,
 }
 
 impl ResourceLedger {
+    pub fn with_clock(clock: Arc<dyn Clock>, lease_ttl: Duration) -> Self {
+        Self {
+            resources: Vec::new(),
+            clock,
+            lease_ttl,
+        }
+    }
+    pub fn lease_ttl(&self) -> Duration {
+        self.lease_ttl
+    }
     pub fn allocated_count(&self, resource: &str) -> usize {
         let mut count = 0;
         for res in &self.resources {
-            if res.1 == resource {
+            if res.resource == resource {
                 count += 1;
             }
         }
@@ -28,52 +81,111 @@ impl ResourceLedger {
     }
     pub fn is_exclusively_locked(&self, resource: &str) -> bool {
         for res in &self.resources {
-            if res.1 == resource && res.2 {
+            if res.resource == resource && res.exclusive {
                 return true;
             }
         }
         false
     }
+    pub fn holders(&self, resource: &str) -> Vec<&LedgerEntry> {
+        self.resources
+            .iter()
+            .filter(|res| res.resource == resource)
+            .collect()
+    }
+    // How many tests hold each resource that is held at all
+    pub fn occupancy(&self) -> BTreeMap<String, usize> {
+        let mut occupancy = BTreeMap::new();
+        for res in &self.resources {
+            *occupancy.entry(res.resource.clone()).or_default() += 1;
+        }
+        occupancy
+    }
     pub fn get_owners(&self, resource: &str) -> Vec<TestID> {
         let mut owners = Vec::new();
         for res in &self.resources {
-            if res.1 == resource {
-                owners.push(res.0);
+            if res.resource == resource {
+                owners.push(res.testid.clone());
             }
         }
         owners
     }
 
+    // Whether acquire_resource would succeed right now
+    pub fn can_acquire(&self, testid: &str, resource: &str, get_exclusive: bool) -> bool {
+        if get_exclusive && self.allocated_count(resource) != 0 {
+            return false;
+        }
+        !self.is_exclusively_locked(resource)
+            && !self
+                .get_owners(resource)
+                .iter()
+                .any(|owner| owner == testid)
+    }
+
     pub fn acquire_resource(
         &mut self,
-        testid: TestID,
+        testid: &str,
+        resource: &str,
+        get_exclusive: bool,
+    ) -> anyhow::Result<()> {
+        self.acquire_wrapped_resource(testid, resource, get_exclusive, None)
+    }
+    // Same as acquire_resource, but records that the lock is held
+    // on behalf of the wrapper apparatus `wrapped_by`
+    pub fn acquire_wrapped_resource(
+        &mut self,
+        testid: &str,
         resource: &str,
         get_exclusive: bool,
+        wrapped_by: Option<&str>,
     ) -> anyhow::Result<()> {
         if get_exclusive && self.allocated_count(resource) != 0 {
             return Err(anyhow!(
-                "Tried to get an exclusive lock on {resource} by {testid} but it is already being used"
+                "Tried to get an exclusive lock on {resource} by {testid} but it is already being used by {}",
+                self.describe_holders(resource)
             ));
         }
         if self.is_exclusively_locked(resource) {
             return Err(anyhow!(
-                "Tried to get a lock on {resource} by {testid} which is already exclusively locked"
+                "Tried to get a lock on {resource} by {testid} which is already exclusively locked by {}",
+                self.describe_holders(resource)
             ));
         }
-        if self.get_owners(resource).contains(&testid) {
+        if self
+            .get_owners(resource)
+            .iter()
+            .any(|owner| owner == testid)
+        {
             return Err(anyhow!(
                 "Tried to get a lock on {resource} by {testid} which it already has aquired"
             ));
         }
 
-        self.resources
-            .push((testid, resource.into(), get_exclusive));
+        self.resources.push(LedgerEntry {
+            testid: testid.into(),
+            resource: resource.into(),
+            exclusive: get_exclusive,
+            wrapped_by: wrapped_by.map(String::from),
+            expires_at: self.clock.now() + self.lease_ttl,
+        });
         Ok(())
     }
-    pub fn release_resource(&mut self, testid: TestID, resource: &str) -> anyhow::Result<()> {
+    fn describe_holders(&self, resource: &str) -> String {
+        let holders: Vec<String> = self
+            .holders(resource)
+            .iter()
+            .map(|holder| match &holder.wrapped_by {
+                Some(wrapper) => format!("{} (through {})", holder.testid, wrapper),
+                None => holder.testid.clone(),
+            })
+            .collect();
+        holders.join(", ")
+    }
+    pub fn release_resource(&mut self, testid: &str, resource: &str) -> anyhow::Result<()> {
         let mut resource_index = None;
         for (idx, res) in self.resources.iter().enumerate() {
-            if res.0 == testid && res.1 == resource {
+            if res.testid == testid && res.resource == resource {
                 resource_index = Some(idx)
             }
         }
@@ -85,17 +197,350 @@ impl ResourceLedger {
         self.resources.swap_remove(resource_index.unwrap());
         Ok(())
     }
+    // Returns every resource that the test was holding
+    pub fn release_all(&mut self, testid: &str) -> Vec<String> {
+        let mut released = Vec::new();
+        self.resources.retain(|res| {
+            if res.testid == testid {
+                released.push(res.resource.clone());
+                return false;
+            }
+            true
+        });
+        released
+    }
+    // Pushes back the expiry of every lease the test holds.
+    // Returns how many leases were renewed.
+    pub fn renew(&mut self, testid: &str) -> usize {
+        let expires_at = self.clock.now() + self.lease_ttl;
+        let mut renewed = 0;
+        for res in self.resources.iter_mut().filter(|res| res.testid == testid) {
+            res.expires_at = expires_at;
+            renewed += 1;
+        }
+        renewed
+    }
+    // Removes and returns every lease that was not renewed in time
+    pub fn reclaim_expired(&mut self) -> Vec<LedgerEntry> {
+        let now = self.clock.now();
+        let mut reclaimed = Vec::new();
+        self.resources.retain(|res| {
+            if res.expires_at <= now {
+                reclaimed.push(res.clone());
+                return false;
+            }
+            true
+        });
+        reclaimed
+    }
+    // Takes the resource away from whoever holds it, whether or not their lease is current.
+    // Anything held as part of a forcibly released wrapper goes with it.
+    pub fn force_release(&mut self, resource: &str) -> Vec<LedgerEntry> {
+        let mut released = Vec::new();
+        let mut to_release: Vec<(Option<TestID>, String)> = vec![(None, resource.into())];
+        while let Some((testid, resource)) = to_release.pop() {
+            self.resources.retain(|res| {
+                let matches = match &testid {
+                    // Wrapped resources are only taken from the test that held the wrapper
+                    Some(testid) => {
+                        res.testid == *testid && res.wrapped_by.as_deref() == Some(&resource)
+                    }
+                    None => res.resource == resource,
+                };
+                if matches {
+                    to_release.push((Some(res.testid.clone()), res.resource.clone()));
+                    released.push(res.clone());
+                }
+                !matches
+            });
+        }
+        released
+    }
+}
+
+// Devices and apparatuses are tracked in separate ledgers
+// so that a device and an apparatus are free to share a name.
+#[derive(Default, Debug, Clone)]
+pub struct LabLedger {
+    pub devices: ResourceLedger,
+    pub apparatuses: ResourceLedger,
+}
+
+impl LabLedger {
+    pub fn with_clock(clock: Arc<dyn Clock>, lease_ttl: Duration) -> Self {
+        Self {
+            devices: ResourceLedger::with_clock(Arc::clone(&clock), lease_ttl),
+            apparatuses: ResourceLedger::with_clock(clock, lease_ttl),
+        }
+    }
+    // Devices are always locked exclusively.
+    // Either everything in the claim is acquired or nothing is.
+    //
+    // Callers hold the whole ledger while acquiring, so two tests racing
+    // for overlapping wrappers cannot each end up with half of what they need
+    // and wait on each other.
+    pub fn acquire_claim(&mut self, testid: &str, claim: &ResourceClaim) -> anyhow::Result<()> {
+        self.devices.acquire_resource(testid, &claim.device, true)?;
+        for (idx, apparatus) in claim.apparatuses.iter().enumerate() {
+            if let Err(err) = self.apparatuses.acquire_wrapped_resource(
+                testid,
+                &apparatus.name,
+                apparatus.exclusive,
+                apparatus.wrapped_by.as_deref(),
+            ) {
+                for acquired in &claim.apparatuses[..idx] {
+                    self.apparatuses.release_resource(testid, &acquired.name)?;
+                }
+                self.devices.release_resource(testid, &claim.device)?;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+    pub fn release_all(&mut self, testid: &str) -> (Vec<String>, Vec<String>) {
+        (
+            self.devices.release_all(testid),
+            self.apparatuses.release_all(testid),
+        )
+    }
+    pub fn renew(&mut self, testid: &str) -> usize {
+        self.devices.renew(testid) + self.apparatuses.renew(testid)
+    }
+    pub fn reclaim_expired(&mut self) -> (Vec<LedgerEntry>, Vec<LedgerEntry>) {
+        (
+            self.devices.reclaim_expired(),
+            self.apparatuses.reclaim_expired(),
+        )
+    }
+}
+
+// The ledger that the stages share.
+// Tests are handed resources by the Aquirer and give them back in the TerminatedSink,
+// which wakes the Aquirer so that it can hand them to whoever is waiting.
+#[derive(Default, Debug)]
+pub struct SharedLedger {
+    ledger: Mutex<LabLedger>,
+    released: Notify,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl SharedLedger {
+    pub fn new(ledger: LabLedger) -> Self {
+        Self {
+            ledger: Mutex::new(ledger),
+            released: Notify::new(),
+            metrics: None,
+        }
+    }
+    // Reclaimed and force released locks are counted here
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+    pub fn lock(&self) -> MutexGuard<'_, LabLedger> {
+        self.ledger.lock().unwrap()
+    }
+    // Renews the test's leases until the returned LeaseRenewal is dropped.
+    // The renewal travels with the test, so a test that is dropped without
+    // terminating (a panicking worker, say) stops renewing and its leases run out.
+    pub fn keep_alive(self: &Arc<Self>, testid: &str) -> LeaseRenewal {
+        let ledger = Arc::clone(self);
+        let testid = testid.to_string();
+        let period = (self.lock().devices.lease_ttl() / 3).max(Duration::from_millis(10));
+        LeaseRenewal(tokio::spawn(async move {
+            let mut heartbeat = tokio::time::interval(period);
+            loop {
+                heartbeat.tick().await;
+                ledger.lock().renew(&testid);
+            }
+        }))
+    }
+    // Reclaims every lease that has run out, reporting each one
+    pub fn reclaim_expired(&self) -> Vec<LedgerEntry> {
+        let (devices, apparatuses) = self.lock().reclaim_expired();
+        self.report("device", "lease_expired", &devices);
+        self.report("apparatus", "lease_expired", &apparatuses);
+        self.reported_release(devices.into_iter().chain(apparatuses).collect())
+    }
+    // For admins to free a device that is stuck.
+    // The test holding it is not stopped.
+    pub fn force_release_device(&self, device: &str) -> Vec<LedgerEntry> {
+        let released = self.lock().devices.force_release(device);
+        self.report("device", "forced", &released);
+        self.reported_release(released)
+    }
+    pub fn force_release_apparatus(&self, apparatus: &str) -> Vec<LedgerEntry> {
+        let released = self.lock().apparatuses.force_release(apparatus);
+        self.report("apparatus", "forced", &released);
+        self.reported_release(released)
+    }
+    fn report(&self, kind: &str, reason: &str, taken: &[LedgerEntry]) {
+        for entry in taken {
+            log::warn!(
+                "Took {} {} from test {} ({})",
+                kind,
+                entry.resource,
+                entry.testid,
+                reason
+            );
+            if let Some(metrics) = &self.metrics {
+                metrics.resource_reclaimed(kind, &entry.resource, reason);
+            }
+        }
+    }
+    fn reported_release(&self, released: Vec<LedgerEntry>) -> Vec<LedgerEntry> {
+        if !released.is_empty() {
+            self.released.notify_one();
+        }
+        released
+    }
+    pub fn release_all(&self, testid: &str) -> (Vec<String>, Vec<String>) {
+        let released = self.lock().release_all(testid);
+        if !released.0.is_empty() || !released.1.is_empty() {
+            // Stores a permit if the Aquirer is busy so that it is not missed
+            self.released.notify_one();
+        }
+        released
+    }
+    // Waits until some test releases its resources
+    pub async fn released(&self) {
+        self.released.notified().await;
+    }
+}
+
+#[derive(Debug)]
+pub struct LeaseRenewal(JoinHandle<()>);
+
+impl Drop for LeaseRenewal {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::resources::ApparatusLock;
+
+    #[derive(Debug)]
+    struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        fn new() -> Arc<Self> {
+            Arc::new(ManualClock(Mutex::new(Instant::now())))
+        }
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_leases_expire_unless_renewed() {
+        let clock = ManualClock::new();
+        let mut ledger = ResourceLedger::with_clock(clock.clone(), Duration::from_secs(60));
+        ledger.acquire_resource("1", "resource1", true).unwrap();
+        ledger.acquire_resource("2", "resource2", true).unwrap();
+
+        clock.advance(Duration::from_secs(45));
+        assert_eq!(ledger.renew("1"), 1);
+        assert!(ledger.reclaim_expired().is_empty());
+
+        clock.advance(Duration::from_secs(15));
+        let reclaimed = ledger.reclaim_expired();
+        assert_eq!(reclaimed.len(), 1);
+        assert_eq!(reclaimed[0].testid, "2");
+        assert!(ledger.can_acquire("3", "resource2", true));
+        assert!(!ledger.can_acquire("3", "resource1", true));
+    }
+
+    #[test]
+    fn test_force_release_takes_wrapped_resources() {
+        let mut ledger = ResourceLedger::default();
+        ledger
+            .acquire_wrapped_resource("1", "combo", true, None)
+            .unwrap();
+        ledger
+            .acquire_wrapped_resource("1", "mic", true, Some("combo"))
+            .unwrap();
+        ledger.acquire_resource("1", "lidar", true).unwrap();
+
+        let released = ledger.force_release("combo");
+        let mut names: Vec<&str> = released.iter().map(|res| res.resource.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["combo", "mic"]);
+        assert_eq!(ledger.get_owners("lidar"), vec!["1"]);
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_stops_when_dropped() {
+        let clock = ManualClock::new();
+        let ledger = Arc::new(SharedLedger::new(LabLedger::with_clock(
+            clock.clone(),
+            Duration::from_millis(60),
+        )));
+        ledger
+            .lock()
+            .devices
+            .acquire_resource("1", "dev1", true)
+            .unwrap();
+        let renewal = ledger.keep_alive("1");
+
+        clock.advance(Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        clock.advance(Duration::from_millis(50));
+        assert!(ledger.reclaim_expired().is_empty());
+
+        drop(renewal);
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(ledger.reclaim_expired().len(), 1);
+    }
+
+    #[test]
+    fn test_reclaims_are_counted() {
+        let clock = ManualClock::new();
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let ledger = SharedLedger::new(LabLedger::with_clock(
+            clock.clone(),
+            Duration::from_secs(60),
+        ))
+        .with_metrics(Arc::clone(&metrics));
+        ledger
+            .lock()
+            .devices
+            .acquire_resource("1", "dev1", true)
+            .unwrap();
+        ledger
+            .lock()
+            .apparatuses
+            .acquire_resource("2", "camera", false)
+            .unwrap();
+
+        assert_eq!(ledger.force_release_apparatus("camera").len(), 1);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(ledger.reclaim_expired().len(), 1);
+
+        let test_map = Mutex::new(crate::running_test_map::RunningTestMap::default());
+        let text = metrics.render(&test_map, &ledger).unwrap();
+        for line in [
+            "htp_ledger_reclaims_total{kind=\"apparatus\",reason=\"forced\",resource=\"camera\"} 1",
+            "htp_ledger_reclaims_total{kind=\"device\",reason=\"lease_expired\",resource=\"dev1\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} missing from\n{}", line, text);
+        }
+    }
 
     #[test]
     fn test_allocated_count() {
         let mut ledger = ResourceLedger::default();
-        let testid1: TestID = 1;
-        let testid2: TestID = 2;
+        let testid1 = "1";
+        let testid2 = "2";
         let resource = "resource1";
 
         ledger.acquire_resource(testid1, resource, false).unwrap();
@@ -114,7 +559,7 @@ mod tests {
     #[test]
     fn test_is_exclusively_locked() {
         let mut ledger = ResourceLedger::default();
-        let testid: TestID = 1;
+        let testid = "1";
         let resource = "resource1";
 
         assert!(!ledger.is_exclusively_locked(resource));
@@ -129,8 +574,8 @@ mod tests {
     #[test]
     fn test_get_owners() {
         let mut ledger = ResourceLedger::default();
-        let testid1: TestID = 1;
-        let testid2: TestID = 2;
+        let testid1 = "1";
+        let testid2 = "2";
         let resource = "resource1";
 
         ledger.acquire_resource(testid1, resource, false).unwrap();
@@ -146,8 +591,8 @@ mod tests {
     #[test]
     fn test_acquire_resource() {
         let mut ledger = ResourceLedger::default();
-        let testid1: TestID = 1;
-        let testid2: TestID = 2;
+        let testid1 = "1";
+        let testid2 = "2";
         let resource = "resource1";
 
         ledger.acquire_resource(testid1, resource, false).unwrap();
@@ -163,11 +608,93 @@ mod tests {
         assert_eq!(ledger.allocated_count(resource), 2);
     }
 
+    #[test]
+    fn test_release_all() {
+        let mut ledger = ResourceLedger::default();
+        ledger.acquire_resource("1", "resource1", false).unwrap();
+        ledger.acquire_resource("2", "resource1", false).unwrap();
+        ledger.acquire_resource("1", "resource2", true).unwrap();
+
+        let mut released = ledger.release_all("1");
+        released.sort();
+        assert_eq!(released, vec!["resource1", "resource2"]);
+        assert_eq!(ledger.get_owners("resource1"), vec!["2"]);
+        assert!(ledger.can_acquire("1", "resource2", true));
+    }
+
+    #[test]
+    fn test_acquire_claim_is_all_or_nothing() {
+        let mut ledger = LabLedger::default();
+        let claim = |device: &str| ResourceClaim {
+            device: device.into(),
+            apparatuses: vec![ApparatusLock {
+                name: "lidar".into(),
+                exclusive: true,
+                wrapped_by: None,
+            }],
+        };
+        ledger.acquire_claim("1", &claim("dev1")).unwrap();
+
+        // dev2 is free but the lidar is not, so dev2 must be left alone
+        assert!(ledger.acquire_claim("2", &claim("dev2")).is_err());
+        assert_eq!(ledger.devices.allocated_count("dev2"), 0);
+
+        ledger.release_all("1");
+        ledger.acquire_claim("2", &claim("dev2")).unwrap();
+    }
+
+    #[test]
+    fn test_overlapping_wrappers() {
+        let mut ledger = LabLedger::default();
+        let wrapper = |device: &str, name: &str, children: &[&str]| {
+            let mut apparatuses = vec![ApparatusLock {
+                name: name.into(),
+                exclusive: true,
+                wrapped_by: None,
+            }];
+            for child in children {
+                apparatuses.push(ApparatusLock {
+                    name: child.to_string(),
+                    exclusive: true,
+                    wrapped_by: Some(name.into()),
+                });
+            }
+            ResourceClaim {
+                device: device.into(),
+                apparatuses,
+            }
+        };
+        ledger
+            .acquire_claim("1", &wrapper("dev1", "mic-webcam", &["mic", "webcam"]))
+            .unwrap();
+
+        // Shares the webcam, so none of it can be taken, not even the free speaker
+        let overlapping = wrapper("dev2", "speaker-webcam", &["speaker", "webcam"]);
+        assert!(ledger.acquire_claim("2", &overlapping).is_err());
+        assert_eq!(ledger.apparatuses.allocated_count("speaker-webcam"), 0);
+        assert_eq!(ledger.apparatuses.allocated_count("speaker"), 0);
+        assert_eq!(ledger.devices.allocated_count("dev2"), 0);
+
+        // Taking a child directly shows which wrapper has it
+        let err = ledger
+            .apparatuses
+            .acquire_resource("3", "mic", true)
+            .unwrap_err();
+        assert!(err.to_string().contains("1 (through mic-webcam)"));
+        assert_eq!(
+            ledger.apparatuses.holders("mic")[0].wrapped_by.as_deref(),
+            Some("mic-webcam")
+        );
+
+        ledger.release_all("1");
+        ledger.acquire_claim("2", &overlapping).unwrap();
+    }
+
     #[test]
     fn test_release_resource() {
         let mut ledger = ResourceLedger::default();
-        let testid1: TestID = 1;
-        let testid2: TestID = 2;
+        let testid1 = "1";
+        let testid2 = "2";
         let resource = "resource1";
 
         ledger.acquire_resource(testid1, resource, false).unwrap();
@@ -1,102 +1,131 @@
-use std::collections::{HashMap, HashSet};
-
 use anyhow::anyhow;
 
-use crate::config::{
-    apparatuses::{Apparatus, ApparatusMap},
-    devices::{Device, DeviceMap},
-    Config,
+use crate::{
+    config::{tests::TestSpecification, Config},
+    resource_ledger::LabLedger,
 };
 
-// The ResourceCollection is the owner and core key/lock mechanism
-// for test-orchestration
-pub struct ResourceCollection<'a> {
-    all_devices: &'a DeviceMap,
-    all_apparatuses: &'a ApparatusMap,
-    owned_devices: HashSet<String>,
-    owned_apparatues: HashSet<String>,
+// The device and apparatuses that a test has locked to run on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceClaim {
+    pub device: String,
+    // The apparatus the test asked for followed by everything it wraps
+    pub apparatuses: Vec<ApparatusLock>,
 }
 
-impl<'a> ResourceCollection<'a> {
-    pub fn new(config: &'a Config) -> ResourceCollection<'a> {
-        ResourceCollection {
-            all_devices: &config.devices,
-            all_apparatuses: &config.apparatuses,
-            owned_devices: HashSet::new(),
-            owned_apparatues: HashSet::new(),
-        }
-    }
-    pub fn insert_device(&mut self, device: &str) -> anyhow::Result<()> {
-        if !self.all_devices.contains_key(device) {
-            return Err(anyhow!(
-                "Resource collection does not contain mapping for device {device}"
-            ));
-        }
-        if self.owned_devices.contains(device) {
-            return Err(anyhow!(
-                "Resource collection already contains device {device}"
-            ));
-        }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApparatusLock {
+    pub name: String,
+    pub exclusive: bool,
+    pub wrapped_by: Option<String>,
+}
 
-        self.owned_devices.insert(device.to_string());
-        return Ok(());
+// The apparatus along with every apparatus it wraps, and everything those wrap.
+// An apparatus that is wrapped more than once only appears once.
+// Wrapped apparatuses are always locked exclusively, since the test may use
+// their hardware through the wrapper.
+pub fn expand_apparatus(config: &Config, apparatus: &str) -> anyhow::Result<Vec<ApparatusLock>> {
+    let mut expanded = Vec::new();
+    expand_into(config, apparatus, None, &mut vec![], &mut expanded)?;
+    Ok(expanded)
+}
+
+fn expand_into(
+    config: &Config,
+    apparatus: &str,
+    wrapped_by: Option<&str>,
+    path: &mut Vec<String>,
+    expanded: &mut Vec<ApparatusLock>,
+) -> anyhow::Result<()> {
+    if path.iter().any(|wrapper| wrapper == apparatus) {
+        return Err(anyhow!(
+            "Apparatus {apparatus} wraps itself through {}",
+            path.join(" -> ")
+        ));
     }
-    pub fn insert_apparatus(&mut self, apparatus: &str) -> anyhow::Result<()> {
-        if !self.all_apparatuses.contains_key(apparatus) {
-            return Err(anyhow!(
-                "Resource collection does not contain mapping for apparatus {apparatus}"
-            ));
-        }
-        let apparatus_meta = self.all_apparatuses.get(apparatus).unwrap();
-        if self.owned_apparatues.contains(apparatus) && apparatus_meta.is_exclusively_locked {
-            return Err(anyhow!(
-                "Resource collection already contains apparatus {apparatus} and it is supposed to be exclusive"
-            ));
-        }
+    let apparatus_meta = config
+        .apparatuses
+        .get(apparatus)
+        .ok_or_else(|| anyhow!("Unknown apparatus {apparatus}"))?;
+    if !expanded.iter().any(|lock| lock.name == apparatus) {
+        expanded.push(ApparatusLock {
+            name: apparatus.into(),
+            exclusive: wrapped_by.is_some() || apparatus_meta.is_exclusively_locked,
+            wrapped_by: wrapped_by.map(String::from),
+        });
+    }
+    path.push(apparatus.into());
+    for wrapped in &apparatus_meta.wrapped_apparatuses {
+        expand_into(config, wrapped, Some(apparatus), path, expanded)?;
+    }
+    path.pop();
+    Ok(())
+}
 
-        self.owned_apparatues.insert(apparatus.to_string());
-        return Ok(());
+// Errors if the test could never be given resources, no matter how long it waited
+pub fn check_satisfiable(config: &Config, spec: &TestSpecification) -> anyhow::Result<()> {
+    expand_apparatus(config, &spec.apparatus)?;
+    if candidate_devices(config, spec).is_empty() {
+        return Err(anyhow!(
+            "No device has apparatus {} connected without being excluded",
+            spec.apparatus
+        ));
     }
+    Ok(())
+}
 
-    // If we have a device to give, return Some(device). This does not also get the apparatus.
-    // however any returned device must also have the desired apparatus connected to it.
-    pub fn take_device(&mut self, device_type: &str, required_apparatus: &str) -> Option<String> {
-        for (device_name, device) in self.all_devices {
-            if device.device_type == device_type
-                && device
-                    .connected_apparatuses
-                    .contains(&required_apparatus.to_string())
-                && self.owned_devices.contains(device_name)
-            {
-                // Remove our ownership and give up ownership
-                self.owned_devices.remove(device_name);
-                return Some(device_name.to_string());
-            }
-        }
-        return None;
+// Every device the test could run on if it were free.
+// Sorted so that the same device is preferred every time.
+pub fn candidate_devices<'a>(config: &'a Config, spec: &TestSpecification) -> Vec<&'a str> {
+    if !config.apparatuses.contains_key(&spec.apparatus) {
+        return Vec::new();
     }
+    let mut candidates: Vec<&str> = config
+        .devices
+        .iter()
+        .filter(|(_, device)| {
+            device.connected_apparatuses.contains(&spec.apparatus)
+                && !spec.excluded_device_types.contains(&device.device_type)
+        })
+        .map(|(device_name, _)| device_name.as_str())
+        .collect();
+    candidates.sort();
+    candidates
+}
 
-    pub fn take_apparatus(&mut self, required_apparatus: &str) -> Option<String> {
-        let apparatus_meta = self.all_apparatuses.get(required_apparatus);
-        if let Some(apparatus_meta) = apparatus_meta {
-            if !self.owned_apparatues.contains(required_apparatus) {
-                return None;
-            }
-            if apparatus_meta.is_exclusively_locked {
-                self.owned_apparatues.remove(required_apparatus);
-            }
-            return Some(required_apparatus.to_string());
-        }
-        // maybe panic? Shouldnt be possible given validation
+// The first candidate device that is free right now,
+// provided the apparatus the test needs (and everything it wraps) is free as well
+pub fn find_claim(
+    ledger: &LabLedger,
+    testid: &str,
+    config: &Config,
+    spec: &TestSpecification,
+) -> Option<ResourceClaim> {
+    let apparatuses = expand_apparatus(config, &spec.apparatus).ok()?;
+    if !apparatuses.iter().all(|apparatus| {
+        ledger
+            .apparatuses
+            .can_acquire(testid, &apparatus.name, apparatus.exclusive)
+    }) {
         return None;
     }
+    let device = candidate_devices(config, spec)
+        .into_iter()
+        .find(|device| ledger.devices.can_acquire(testid, device, true))?;
+    Some(ResourceClaim {
+        device: device.into(),
+        apparatuses,
+    })
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use super::*;
+    use crate::config::{apparatuses::Apparatus, devices::Device};
 
-    fn setup() -> ResourceCollection<'static> {
+    fn setup_config() -> Config {
         let mut config = Config::default();
         config.devices.insert(
             "dev1".into(),
@@ -130,85 +159,149 @@ mod tests {
                 wrapped_apparatuses: vec![],
             },
         );
-        ResourceCollection::new(Box::leak(Box::new(config)))
+        config
     }
 
-    #[test]
-    fn test_insert_device() {
-        let mut rc = setup();
-        assert!(rc.insert_device("dev1").is_ok());
-        assert_eq!(rc.owned_devices.contains("dev1"), true);
+    fn spec(apparatus: &str, excluded_device_types: Vec<String>) -> TestSpecification {
+        TestSpecification {
+            name: "test".into(),
+            dependencies: HashMap::new(),
+            excluded_device_types,
+            apparatus: apparatus.into(),
+            robot_config: "".into(),
+            remote_test_script: None,
+            on_device_test_script: None,
+            max_duration_sec: None,
+        }
     }
 
     #[test]
-    fn test_insert_device_already_owned() {
-        let mut rc = setup();
-        rc.insert_device("dev1").unwrap();
-        assert!(rc.insert_device("dev1").is_err());
+    fn test_candidate_devices() {
+        let config = setup_config();
+        assert_eq!(
+            candidate_devices(&config, &spec("app1", vec![])),
+            vec!["dev1", "dev2"]
+        );
+        assert_eq!(
+            candidate_devices(&config, &spec("app2", vec![])),
+            vec!["dev2"]
+        );
+        assert!(candidate_devices(&config, &spec("app1", vec!["linux".into()])).is_empty());
+        assert!(candidate_devices(&config, &spec("app_invalid", vec![])).is_empty());
     }
 
-    #[test]
-    fn test_insert_device_not_present() {
-        let mut rc = setup();
-        assert!(rc.insert_device("dev_invalid").is_err());
+    fn add_wrapper(config: &mut Config, name: &str, wrapped: &[&str]) {
+        config.apparatuses.insert(
+            name.into(),
+            Apparatus {
+                wrapped_apparatuses: wrapped.iter().map(|w| w.to_string()).collect(),
+                ..Default::default()
+            },
+        );
     }
 
     #[test]
-    fn test_insert_apparatus() {
-        let mut rc = setup();
-        assert!(rc.insert_apparatus("app1").is_ok());
-        assert_eq!(rc.owned_apparatues.contains("app1"), true);
-    }
+    fn test_expand_apparatus() {
+        let mut config = setup_config();
+        add_wrapper(&mut config, "combo", &["app1", "app2"]);
+        add_wrapper(&mut config, "big-combo", &["combo", "app1"]);
 
-    #[test]
-    fn test_insert_apparatus_already_owned() {
-        let mut rc = setup();
-        rc.insert_apparatus("app1").unwrap();
-        assert!(rc.insert_apparatus("app1").is_err());
-    }
+        let expanded = expand_apparatus(&config, "big-combo").unwrap();
+        let names: Vec<&str> = expanded.iter().map(|lock| lock.name.as_str()).collect();
+        assert_eq!(names, vec!["big-combo", "combo", "app1", "app2"]);
+        assert_eq!(expanded[0].wrapped_by, None);
+        assert_eq!(expanded[2].wrapped_by.as_deref(), Some("combo"));
 
-    #[test]
-    fn test_insert_apparatus_not_present() {
-        let mut rc = setup();
-        assert!(rc.insert_apparatus("app_invalid").is_err());
+        add_wrapper(&mut config, "loop-a", &["loop-b"]);
+        add_wrapper(&mut config, "loop-b", &["loop-a"]);
+        assert!(expand_apparatus(&config, "loop-a").is_err());
+        add_wrapper(&mut config, "broken", &["app_invalid"]);
+        assert!(expand_apparatus(&config, "broken").is_err());
     }
 
     #[test]
-    fn test_take_device() {
-        let mut rc = setup();
-        rc.insert_device("dev1").unwrap();
-        let device = rc.take_device("linux", "app1");
-        assert_eq!(device, Some("dev1".to_string()));
-        assert_eq!(rc.owned_devices.contains("dev1"), false);
-    }
+    fn test_expand_apparatus_wrapped_are_exclusive() {
+        let mut config = setup_config();
+        config
+            .apparatuses
+            .get_mut("app1")
+            .unwrap()
+            .is_exclusively_locked = false;
+        add_wrapper(&mut config, "combo", &["app1"]);
+        config
+            .apparatuses
+            .get_mut("combo")
+            .unwrap()
+            .is_exclusively_locked = false;
 
-    #[test]
-    fn test_take_device_no_suitable_device() {
-        let mut rc = setup();
-        let device = rc.take_device("linux", "app_invalid");
-        assert_eq!(device, None);
-    }
+        let expanded = expand_apparatus(&config, "combo").unwrap();
+        assert!(!expanded[0].exclusive);
+        assert!(expanded[1].exclusive);
+        // Asked for directly, app1 can still be shared
+        assert!(!expand_apparatus(&config, "app1").unwrap()[0].exclusive);
 
-    #[test]
-    fn test_take_apparatus() {
-        let mut rc = setup();
-        rc.insert_apparatus("app1").unwrap();
-        let apparatus = rc.take_apparatus("app1");
-        assert_eq!(apparatus, Some("app1".to_string()));
-        assert_eq!(rc.owned_apparatues.contains("app1"), false);
+        let mut ledger = LabLedger::default();
+        ledger
+            .apparatuses
+            .acquire_resource("1", "app1", false)
+            .unwrap();
+        config
+            .devices
+            .get_mut("dev1")
+            .unwrap()
+            .connected_apparatuses
+            .push("combo".into());
+        assert_eq!(
+            find_claim(&ledger, "2", &config, &spec("combo", vec![])),
+            None
+        );
     }
 
     #[test]
-    fn test_take_apparatus_not_owned() {
-        let mut rc = setup();
-        let apparatus = rc.take_apparatus("app1");
-        assert_eq!(apparatus, None);
+    fn test_find_claim_locks_wrapped() {
+        let mut config = setup_config();
+        add_wrapper(&mut config, "combo", &["app1"]);
+        config
+            .devices
+            .get_mut("dev1")
+            .unwrap()
+            .connected_apparatuses
+            .push("combo".into());
+        let mut ledger = LabLedger::default();
+
+        let claim = find_claim(&ledger, "1", &config, &spec("combo", vec![])).unwrap();
+        ledger.acquire_claim("1", &claim).unwrap();
+        assert_eq!(ledger.apparatuses.get_owners("app1"), vec!["1"]);
+        // app1 is inside the combo, so it is not available on dev2 either
+        assert_eq!(
+            find_claim(&ledger, "2", &config, &spec("app1", vec![])),
+            None
+        );
     }
 
     #[test]
-    fn test_take_apparatus_not_present() {
-        let mut rc = setup();
-        let apparatus = rc.take_apparatus("app_invalid");
-        assert_eq!(apparatus, None);
+    fn test_find_claim_waits_for_resources() {
+        let config = setup_config();
+        let mut ledger = LabLedger::default();
+        let needs_app2 = spec("app2", vec![]);
+
+        let claim = find_claim(&ledger, "1", &config, &needs_app2).unwrap();
+        assert_eq!(claim.device, "dev2");
+        ledger.acquire_claim("1", &claim).unwrap();
+
+        // dev2 is the only device with app2
+        assert_eq!(find_claim(&ledger, "2", &config, &needs_app2), None);
+        // app1 is exclusive but dev1 is free, so app1 is what blocks it
+        ledger
+            .apparatuses
+            .acquire_resource("3", "app1", true)
+            .unwrap();
+        assert_eq!(
+            find_claim(&ledger, "2", &config, &spec("app1", vec![])),
+            None
+        );
+
+        ledger.release_all("1");
+        assert!(find_claim(&ledger, "2", &config, &needs_app2).is_some());
     }
 }
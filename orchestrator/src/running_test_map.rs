@@ -1,6 +1,11 @@
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-use crate::{config::tests::TestSpecificationID, htp_test::TestID};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::tests::TestSpecificationID,
+    journal::{JournalEntry, JournalWriter},
+};
 
 // This system needs
 // a new name and a revamp
@@ -8,11 +13,108 @@ use crate::{config::tests::TestSpecificationID, htp_test::TestID};
 #[derive(Debug)]
 pub struct RunningTestMapEntry {
     pub id: TestSpecificationID,
+    // The TestID of this run
     pub ver: String,
     pub stage: String,
     pub entry_time: SystemTime,
+    // The test's own cancellation token, so that it can be cancelled by id
+    pub cancel: CancellationToken,
+    // Set when someone asked for this test to be cancelled,
+    // as opposed to it being cancelled by the orchestrator shutting down
+    pub cancel_requested: bool,
 }
 #[derive(Default, Debug)]
 pub struct RunningTestMap {
     pub map: Vec<RunningTestMapEntry>,
+    // When present, every submission and stage change
+    // is also written here so that it survives a crash.
+    // Entries are sent while the map is locked, which keeps them in order,
+    // but written on the journal's own thread.
+    pub journal: Option<JournalWriter>,
+}
+
+impl RunningTestMap {
+    pub fn with_journal(journal: JournalWriter) -> Self {
+        Self {
+            map: Vec::new(),
+            journal: Some(journal),
+        }
+    }
+    pub fn insert(&mut self, priority: usize, entry: RunningTestMapEntry) {
+        self.journal(JournalEntry::Submitted {
+            id: entry.ver.clone(),
+            test_spec_id: entry.id.clone(),
+            priority,
+            time: chrono::offset::Utc::now(),
+        });
+        self.map.push(entry);
+    }
+    // Returns the stage the test left and how long it spent there,
+    // or None if the test has already been removed
+    pub fn set_stage(&mut self, test_id: &str, stage: String) -> Option<(String, Duration)> {
+        let map_entry = self.map.iter_mut().find(|p| p.ver == test_id)?;
+        let spent = map_entry.entry_time.elapsed().unwrap_or_default();
+        map_entry.entry_time = SystemTime::now();
+        let left = std::mem::replace(&mut map_entry.stage, stage.clone());
+        self.journal(JournalEntry::StageChanged {
+            id: test_id.into(),
+            stage,
+            time: chrono::offset::Utc::now(),
+        });
+        Some((left, spent))
+    }
+    pub fn get(&self, test_id: &str) -> Option<&RunningTestMapEntry> {
+        self.map.iter().find(|p| p.ver == test_id)
+    }
+    // Cancels the test, which stops whatever it is doing and sends it to termination.
+    // Returns the stage it was in, or None if there is no such test.
+    pub fn cancel(&mut self, test_id: &str) -> Option<String> {
+        let map_entry = self.map.iter_mut().find(|p| p.ver == test_id)?;
+        map_entry.cancel_requested = true;
+        map_entry.cancel.cancel();
+        Some(map_entry.stage.clone())
+    }
+    pub fn cancel_requested(&self, test_id: &str) -> bool {
+        self.get(test_id)
+            .is_some_and(|map_entry| map_entry.cancel_requested)
+    }
+    pub fn remove(&mut self, test_id: &str) {
+        self.map.retain(|p| p.ver != test_id);
+    }
+    fn journal(&self, entry: JournalEntry) {
+        if let Some(journal) = &self.journal {
+            journal.send(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let mut map = RunningTestMap::default();
+        let cancel = CancellationToken::new();
+        map.insert(
+            2,
+            RunningTestMapEntry {
+                id: ("general".into(), "simpleconn".into()),
+                ver: "a".into(),
+                stage: "Queued".into(),
+                entry_time: SystemTime::now(),
+                cancel: cancel.clone(),
+                cancel_requested: false,
+            },
+        );
+        let (left, _) = map.set_stage("a", "Prepared".into()).unwrap();
+        assert_eq!(left, "Queued");
+        assert!(map.set_stage("b", "Prepared".into()).is_none());
+        assert!(!map.cancel_requested("a"));
+
+        assert_eq!(map.cancel("a"), Some("Prepared".into()));
+        assert!(cancel.is_cancelled());
+        assert!(map.cancel_requested("a"));
+        assert_eq!(map.cancel("b"), None);
+    }
 }
@@ -1,63 +1,147 @@
-use std::{marker::PhantomData, time::SystemTime};
+use std::{sync::Arc, time::Duration};
 
-use anyhow::anyhow;
-use crossbeam::channel::{Receiver, Sender};
+use tokio::sync::{mpsc::UnboundedSender, Notify};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    config::Config,
-    htp_test::{HtpTest, Prepared, Queued, Runnable, Terminated, Validated},
+    htp_test::{HtpTest, Prepared, Runnable, Terminated, TerminationReason},
+    priority_queue::{PriorityReceiver, PrioritySender},
+    resource_ledger::{LabLedger, SharedLedger},
+    resources::{self, ResourceClaim},
+    statistics::{Stage, StageStatus},
 };
 
+// Unlike the other stages the Aquirer is a single scheduler rather than a pool of workers.
+//
+// Tests that cannot get a device and apparatus yet are left waiting in the input channel,
+// where they keep their place in line (and keep aging). Every time a test arrives
+// or resources are released, the most important test that can be satisfied is handed out.
 pub struct Aquirer {
-    input: Receiver<HtpTest<Prepared>>,
-    output: Sender<HtpTest<Runnable>>,
-    output_terminated: Sender<HtpTest<Terminated>>,
+    input: PriorityReceiver<HtpTest<Prepared>>,
+    output: PrioritySender<HtpTest<Runnable>>,
+    output_terminated: UnboundedSender<HtpTest<Terminated>>,
+    ledger: Arc<SharedLedger>,
+    shutdown: CancellationToken,
+    // Fired when a single test is cancelled so that it is not left waiting
+    test_cancelled: Arc<Notify>,
 }
+
+// How often to look for leases that have run out,
+// given that a lease is held for `lease_ttl`
+fn reclaim_interval(lease_ttl: Duration) -> Duration {
+    (lease_ttl / 3).max(Duration::from_millis(10))
+}
+
 impl Aquirer {
     pub fn new(
-        input: Receiver<HtpTest<Prepared>>,
-        output: Sender<HtpTest<Runnable>>,
-        output_terminated: Sender<HtpTest<Terminated>>,
+        input: PriorityReceiver<HtpTest<Prepared>>,
+        output: PrioritySender<HtpTest<Runnable>>,
+        output_terminated: UnboundedSender<HtpTest<Terminated>>,
+        ledger: Arc<SharedLedger>,
+        shutdown: CancellationToken,
+        test_cancelled: Arc<Notify>,
     ) -> Self {
         Self {
             input,
             output,
             output_terminated,
+            ledger,
+            shutdown,
+            test_cancelled,
         }
     }
-    // Dont put a value greater than 5sec. That would be stupid
-    pub fn desired_poll_delay(&mut self) -> tokio::time::Duration {
-        tokio::time::Duration::from_millis(100)
-    }
-    pub fn process_one(&mut self) -> anyhow::Result<()> {
-        let Ok(mut to_aquire) = self.input.try_recv() else {
-            return Ok(());
-        };
-
-        to_aquire.stats_sink.write("aquisition", "started");
-        let aquired = to_aquire.aquire();
-        match aquired {
-            Ok(mut aquired) => {
-                aquired
-                    .stats_sink
-                    .write("aquisition", "finished successfully");
-
-                self.output.send(aquired)?
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let lease_ttl = self.ledger.lock().devices.lease_ttl();
+        let mut reclaim = tokio::time::interval(reclaim_interval(lease_ttl));
+        loop {
+            self.aquire_available()?;
+            if self.input.is_closed() && self.input.is_empty() {
+                break;
             }
-            Err(mut aquire_error) => {
-                aquire_error
-                    .terminated
-                    .stats_sink
-                    .write("aquisition", "failed");
-                println!("Err: {}", aquire_error.msg);
-                self.output_terminated.send(aquire_error.terminated)?
+            tokio::select! {
+                _ = self.input.changed() => {}
+                _ = self.ledger.released() => {}
+                _ = reclaim.tick() => {}
+                // Waiting tests are cancelled along with the orchestrator
+                // and need to be sent on to termination
+                _ = self.shutdown.cancelled(), if !self.shutdown.is_cancelled() => {}
+                _ = self.test_cancelled.notified() => {}
             }
-        };
-        println!("Aquired");
+        }
+        log::info!("Aquirer closing");
         Ok(())
     }
-    pub fn close(&mut self) -> anyhow::Result<()> {
-        println!("Closing");
+    fn aquire_available(&mut self) -> anyhow::Result<()> {
+        while let Some((cancelled, ())) = self
+            .input
+            .take_where(|test| test.cancel.is_cancelled().then_some(()))
+        {
+            self.output_terminated
+                .send(cancelled.terminate(TerminationReason::OrchestratorShutdown))?;
+        }
+        self.ledger.reclaim_expired();
+        // Waiting would never help these
+        while let Some((unrunnable, err)) = self.input.take_where(|test| {
+            resources::check_satisfiable(test.config(), test.get_test_spec()).err()
+        }) {
+            unrunnable.stats_sink.write(
+                Stage::Acquisition,
+                StageStatus::Failed(format!("{:#}", err)),
+            );
+            log::error!(
+                "Test {} can never aquire resources: {:#}",
+                unrunnable.id,
+                err
+            );
+            let reason = TerminationReason::from_error("Cannot aquire resources", &err);
+            self.output_terminated.send(unrunnable.terminate(reason))?;
+        }
+        loop {
+            let mut ledger = self.ledger.lock();
+            let Some((to_aquire, claim)) = self.input.take_where(|test| {
+                resources::find_claim(&ledger, &test.id, test.config(), test.get_test_spec())
+            }) else {
+                break;
+            };
+            to_aquire
+                .stats_sink
+                .write(Stage::Acquisition, StageStatus::Started);
+            let aquired = to_aquire.aquire(&mut ledger, claim);
+            drop(ledger);
+            match aquired {
+                Ok(mut aquired) => {
+                    aquired.stage_success.resource_aquisition = true;
+                    aquired.lease = Some(self.ledger.keep_alive(&aquired.id));
+                    aquired
+                        .stats_sink
+                        .write(Stage::Acquisition, StageStatus::Finished);
+
+                    self.output.send(aquired)?
+                }
+                Err(mut aquire_error) => {
+                    aquire_error.terminated.stats_sink.write(
+                        Stage::Acquisition,
+                        StageStatus::Failed(format!(
+                            "{}: {:#}",
+                            aquire_error.msg, aquire_error.source
+                        )),
+                    );
+                    log::error!(
+                        "Test {} {}: {:#}",
+                        aquire_error.terminated.id,
+                        aquire_error.msg,
+                        aquire_error.source
+                    );
+                    aquire_error
+                        .terminated
+                        .termination_reason
+                        .get_or_insert_with(|| {
+                            TerminationReason::from_error(&aquire_error.msg, &aquire_error.source)
+                        });
+                    self.output_terminated.send(*aquire_error.terminated)?
+                }
+            };
+        }
         Ok(())
     }
 }
@@ -68,20 +152,38 @@ pub struct AquisitionError {
     msg: String,
     #[source]
     source: anyhow::Error,
-    terminated: HtpTest<Terminated>,
+    terminated: Box<HtpTest<Terminated>>,
 }
 
 impl HtpTest<Prepared> {
-    pub fn aquire(self) -> Result<HtpTest<Runnable>, AquisitionError> {
-        // TODO
-        let ok: anyhow::Result<()> = Ok(());
-
-        match ok {
-            Ok(()) => Ok(self.clone_into()),
+    pub fn aquire(
+        mut self,
+        ledger: &mut LabLedger,
+        claim: ResourceClaim,
+    ) -> Result<HtpTest<Runnable>, AquisitionError> {
+        match ledger.acquire_claim(&self.id, &claim) {
+            Ok(()) => {
+                let apparatuses: Vec<&str> = claim
+                    .apparatuses
+                    .iter()
+                    .map(|apparatus| apparatus.name.as_str())
+                    .collect();
+                log::info!(
+                    "Test {} aquired device {} and apparatuses {:?}",
+                    self.id,
+                    claim.device,
+                    apparatuses
+                );
+                self.stats_sink.set_label("device", &claim.device);
+                self.stats_sink
+                    .set_label("apparatus", apparatuses.join(","));
+                self.claim = Some(claim);
+                Ok(self.clone_into())
+            }
             Err(err) => Err(AquisitionError {
-                msg: "Config creation failed".into(),
+                msg: "Failed to lock resources".into(),
                 source: err,
-                terminated: self.clone_into(),
+                terminated: Box::new(self.clone_into()),
             }),
         }
     }
@@ -1,5 +1,132 @@
+use std::{future::Future, sync::Arc};
+
+use futures::FutureExt;
+use tokio::{
+    sync::{mpsc::UnboundedSender, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    htp_test::{HtpTest, Terminated, TerminationReason, TestStage},
+    priority_queue::StageInput,
+};
+
 pub mod aquiring;
 pub mod preperation;
 pub mod running;
 pub mod termination;
 pub mod validation;
+
+// Drives a single stage.
+//
+// Tests are pulled off of `input` as soon as they arrive and handed to `process`,
+// with at most `concurrency` of them being processed at the same time.
+// A permit is taken before a test is pulled so that tests stay in the channel
+// (and in order) until the stage actually has room for them.
+// This matters for priority channels, where the order is only decided on receipt.
+//
+// Returns once `input` has been closed and every in-flight test has been processed.
+pub async fn run_worker<T, I, F, Fut>(
+    stage_name: &'static str,
+    mut input: I,
+    concurrency: usize,
+    mut process: F,
+) -> anyhow::Result<()>
+where
+    I: StageInput<T>,
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut in_flight = JoinSet::new();
+    loop {
+        let permit = Arc::clone(&permits).acquire_owned().await?;
+        let Some(test) = input.recv().await else {
+            break;
+        };
+        let processing = process(test);
+        in_flight.spawn(async move {
+            let result = processing.await;
+            drop(permit);
+            result
+        });
+        // Reap anything that has already finished without waiting on the rest
+        while let Some(Some(finished)) = in_flight.join_next().now_or_never() {
+            log_worker_result(stage_name, finished);
+        }
+    }
+    while let Some(finished) = in_flight.join_next().await {
+        log_worker_result(stage_name, finished);
+    }
+    log::info!("{} closing", stage_name);
+    Ok(())
+}
+
+// Tests that were cancelled while they were waiting for a stage
+// skip the stage and go straight to termination.
+pub fn forward_if_cancelled<Stage: TestStage>(
+    test: HtpTest<Stage>,
+    output_terminated: &UnboundedSender<HtpTest<Terminated>>,
+) -> anyhow::Result<Option<HtpTest<Stage>>> {
+    if !test.cancel.is_cancelled() {
+        return Ok(Some(test));
+    }
+    output_terminated.send(test.terminate(TerminationReason::OrchestratorShutdown))?;
+    Ok(None)
+}
+
+fn log_worker_result(
+    stage_name: &'static str,
+    finished: Result<anyhow::Result<()>, tokio::task::JoinError>,
+) {
+    match finished {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => log::error!("{} failed to process a test: {:?}", stage_name, err),
+        Err(err) => log::error!("{} worker task died: {:?}", stage_name, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_worker_limits_concurrency() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let processed = Arc::new(AtomicUsize::new(0));
+        for i in 0..10 {
+            sender.send(i).unwrap();
+        }
+        drop(sender);
+
+        let (running_inst, max_running_inst, processed_inst) = (
+            Arc::clone(&running),
+            Arc::clone(&max_running),
+            Arc::clone(&processed),
+        );
+        run_worker("test", receiver, 3, move |_: i32| {
+            let running = Arc::clone(&running_inst);
+            let max_running = Arc::clone(&max_running_inst);
+            let processed = Arc::clone(&processed_inst);
+            async move {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                processed.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(processed.load(Ordering::SeqCst), 10);
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+    }
+}
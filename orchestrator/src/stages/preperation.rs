@@ -1,67 +1,91 @@
-use std::{marker::PhantomData, time::SystemTime};
+use std::time::Instant;
 
 use anyhow::anyhow;
-use crossbeam::channel::{Receiver, Sender};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
 use crate::{
-    config::{
-        device_types::{DeviceClassification, DockerSpec},
-        Config,
-    },
-    environment::docker_env::DockerEnvironment,
-    htp_test::{HtpTest, Prepared, Queued, Runnable, Terminated, Validated},
+    htp_test::{HtpTest, Prepared, Terminated, TerminationReason, Validated},
+    priority_queue::PrioritySender,
+    statistics::{Stage, StageStatus},
 };
 
 pub struct Preparer {
-    input: Receiver<HtpTest<Validated>>,
-    output: Sender<HtpTest<Prepared>>,
-    output_terminated: Sender<HtpTest<Terminated>>,
+    input: UnboundedReceiver<HtpTest<Validated>>,
+    output: PrioritySender<HtpTest<Prepared>>,
+    output_terminated: UnboundedSender<HtpTest<Terminated>>,
+    concurrency: usize,
 }
 impl Preparer {
     pub fn new(
-        input: Receiver<HtpTest<Validated>>,
-        output: Sender<HtpTest<Prepared>>,
-        output_terminated: Sender<HtpTest<Terminated>>,
+        input: UnboundedReceiver<HtpTest<Validated>>,
+        output: PrioritySender<HtpTest<Prepared>>,
+        output_terminated: UnboundedSender<HtpTest<Terminated>>,
+        concurrency: usize,
     ) -> Self {
         Self {
             input,
             output,
             output_terminated,
+            concurrency,
         }
     }
-    // Dont put a value greater than 5sec. That would be stupid
-    pub fn desired_poll_delay(&mut self) -> tokio::time::Duration {
-        tokio::time::Duration::from_millis(100)
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            input,
+            output,
+            output_terminated,
+            concurrency,
+        } = self;
+        super::run_worker("Preparer", input, concurrency, move |to_prepare| {
+            Self::process_one(to_prepare, output.clone(), output_terminated.clone())
+        })
+        .await
     }
-    pub async fn process_one(&mut self) -> anyhow::Result<()> {
-        let Ok(mut to_prepare) = self.input.try_recv() else {
+    async fn process_one(
+        to_prepare: HtpTest<Validated>,
+        output: PrioritySender<HtpTest<Prepared>>,
+        output_terminated: UnboundedSender<HtpTest<Terminated>>,
+    ) -> anyhow::Result<()> {
+        let Some(to_prepare) = super::forward_if_cancelled(to_prepare, &output_terminated)? else {
             return Ok(());
         };
-
-        to_prepare.stats_sink.write("preperation", "started");
+        to_prepare
+            .stats_sink
+            .write(Stage::Preparation, StageStatus::Started);
         let prepared = to_prepare.prepare().await;
         match prepared {
             Ok(mut prepared) => {
+                prepared.stage_success.dependency_building = true;
                 prepared
                     .stats_sink
-                    .write("preperation", "finished successfully");
+                    .write(Stage::Preparation, StageStatus::Finished);
 
-                self.output.send(prepared)?
+                log::debug!("Test {} prepared", prepared.id);
+                output.send(prepared)?
             }
             Err(mut prepare_error) => {
+                prepare_error.terminated.stats_sink.write(
+                    Stage::Preparation,
+                    StageStatus::Failed(format!(
+                        "{}: {:#}",
+                        prepare_error.msg, prepare_error.source
+                    )),
+                );
+                log::error!(
+                    "Test {} {}: {:#}",
+                    prepare_error.terminated.id,
+                    prepare_error.msg,
+                    prepare_error.source
+                );
                 prepare_error
                     .terminated
-                    .stats_sink
-                    .write("preperation", "failed");
-                println!("Err: {}", prepare_error.msg);
-                self.output_terminated.send(prepare_error.terminated)?
+                    .termination_reason
+                    .get_or_insert_with(|| {
+                        TerminationReason::from_error(&prepare_error.msg, &prepare_error.source)
+                    });
+                output_terminated.send(*prepare_error.terminated)?
             }
         };
-        println!("Prepared");
-        Ok(())
-    }
-    pub fn close(&mut self) -> anyhow::Result<()> {
-        println!("Closing");
         Ok(())
     }
 }
@@ -72,7 +96,7 @@ pub struct PreperationError {
     msg: String,
     #[source]
     source: anyhow::Error,
-    terminated: HtpTest<Terminated>,
+    terminated: Box<HtpTest<Terminated>>,
 }
 
 impl HtpTest<Validated> {
@@ -88,12 +112,18 @@ impl HtpTest<Validated> {
                 .ok_or(anyhow!("Failed to find device type"))
                 .unwrap();
             // validation ensures this exists
-            let build_result = dep.build(&build_target).await;
+            let started = Instant::now();
+            let build_result = dep
+                .build(build_target, &self.id, &self.cancel, &self.stats_sink)
+                .await;
+            if let Some(metrics) = self.stats_sink.metrics() {
+                metrics.dependency_built(&dep.name, build_result.is_ok(), started.elapsed());
+            }
             if let Err(build_result) = build_result {
                 return Err(PreperationError {
                     msg: "Failed to build dep".into(),
                     source: build_result,
-                    terminated: self.clone_into(),
+                    terminated: Box::new(self.clone_into()),
                 });
             }
         }
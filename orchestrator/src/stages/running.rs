@@ -1,65 +1,91 @@
-use std::{
-    collections::HashMap, marker::PhantomData, path::PathBuf, process::Command, time::SystemTime,
-};
+use std::{collections::HashMap, time::Duration};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use bollard::service::PortBinding;
-use crossbeam::channel::{Receiver, Sender};
-use futures_util::__private::async_await;
+use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{
-    config::{device_types::DockerSpec, Config},
-    environment::docker_env::DockerEnvironment,
-    htp_test::{
-        EnvironmentMountMap, HtpTest, MountMapSet, Queued, Runnable, Terminated, Validated,
-    },
+    config::device_types::{DeviceClassification, DockerSpec},
+    environment::{docker_env::DockerEnvironment, with_timeout, ExecOutput},
+    htp_test::{EnvironmentMountMap, HtpTest, Runnable, Terminated, TerminationReason},
+    priority_queue::PriorityReceiver,
+    statistics::{Stage, StageStatus, UtilizationSummary},
+    stats_file::StatsFile,
 };
 
+// How often values the test writes to HTP_STATS are picked up
+const STATS_FILE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Runner {
-    input: Receiver<HtpTest<Runnable>>,
-    output: Sender<HtpTest<Terminated>>,
-    output_terminated: Sender<HtpTest<Terminated>>,
+    input: PriorityReceiver<HtpTest<Runnable>>,
+    output: UnboundedSender<HtpTest<Terminated>>,
+    output_terminated: UnboundedSender<HtpTest<Terminated>>,
+    concurrency: usize,
 }
 impl Runner {
     pub fn new(
-        input: Receiver<HtpTest<Runnable>>,
-        output: Sender<HtpTest<Terminated>>,
-        output_terminated: Sender<HtpTest<Terminated>>,
+        input: PriorityReceiver<HtpTest<Runnable>>,
+        output: UnboundedSender<HtpTest<Terminated>>,
+        output_terminated: UnboundedSender<HtpTest<Terminated>>,
+        concurrency: usize,
     ) -> Self {
         Self {
             input,
             output,
             output_terminated,
+            concurrency,
         }
     }
-    // Dont put a value greater than 5sec. That would be stupid
-    pub fn desired_poll_delay(&mut self) -> tokio::time::Duration {
-        tokio::time::Duration::from_millis(100)
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            input,
+            output,
+            output_terminated,
+            concurrency,
+        } = self;
+        super::run_worker("Runner", input, concurrency, move |to_run| {
+            Self::process_one(to_run, output.clone(), output_terminated.clone())
+        })
+        .await
     }
-    pub async fn process_one(&mut self) -> anyhow::Result<()> {
-        let Ok(mut to_run) = self.input.try_recv() else {
+    async fn process_one(
+        to_run: HtpTest<Runnable>,
+        output: UnboundedSender<HtpTest<Terminated>>,
+        output_terminated: UnboundedSender<HtpTest<Terminated>>,
+    ) -> anyhow::Result<()> {
+        let Some(to_run) = super::forward_if_cancelled(to_run, &output_terminated)? else {
             return Ok(());
         };
-
-        to_run.stats_sink.write("running", "started");
+        to_run
+            .stats_sink
+            .write(Stage::Running, StageStatus::Started);
         let rund = to_run.run().await;
         match rund {
-            Ok(mut rund) => {
-                rund.stats_sink.write("running", "finished successfully");
-
-                self.output.send(rund)?
+            Ok(rund) => {
+                rund.stats_sink.write(Stage::Running, StageStatus::Finished);
+                log::debug!("Test {} ran", rund.id);
+                output.send(rund)?
             }
             Err(mut run_error) => {
-                run_error.terminated.stats_sink.write("running", "failed");
-                println!("Err: {}", run_error.msg);
-                self.output_terminated.send(run_error.terminated)?
+                run_error.terminated.stats_sink.write(
+                    Stage::Running,
+                    StageStatus::Failed(format!("{}: {:#}", run_error.msg, run_error.source)),
+                );
+                log::error!(
+                    "Test {} {}: {:#}",
+                    run_error.terminated.id,
+                    run_error.msg,
+                    run_error.source
+                );
+                run_error
+                    .terminated
+                    .termination_reason
+                    .get_or_insert_with(|| {
+                        TerminationReason::from_error(&run_error.msg, &run_error.source)
+                    });
+                output_terminated.send(*run_error.terminated)?
             }
         };
-        println!("Ran");
-        Ok(())
-    }
-    pub fn close(&mut self) -> anyhow::Result<()> {
-        println!("Closing");
         Ok(())
     }
 }
@@ -70,15 +96,29 @@ pub struct RunningError {
     msg: String,
     #[source]
     source: anyhow::Error,
-    terminated: HtpTest<Terminated>,
+    terminated: Box<HtpTest<Terminated>>,
 }
 
 impl HtpTest<Runnable> {
     pub async fn run(mut self) -> Result<HtpTest<Terminated>, RunningError> {
-        // TODO support non-docker
-        let spec = DockerSpec {
-            image: "ghcr.io/viamrobotics/canon:amd64-cache".into(),
-            htp_root: "/htp".into(),
+        self.execution_start_time = Some(chrono::offset::Utc::now());
+        // Checked before a container is created for it
+        let Some(command) = self.get_test_spec().on_device_test_script.clone() else {
+            return Err(RunningError {
+                msg: "Test has no on_device_test_script".into(),
+                source: anyhow!("Nothing to run on the device"),
+                terminated: Box::new(self.clone_into()),
+            });
+        };
+        let spec = match self.docker_spec() {
+            Ok(spec) => spec,
+            Err(err) => {
+                return Err(RunningError {
+                    msg: "Cannot run on the aquired device".into(),
+                    source: err,
+                    terminated: Box::new(self.clone_into()),
+                })
+            }
         };
 
         let test_mount_map = self.test_mount_map(&spec.htp_root);
@@ -98,12 +138,15 @@ impl HtpTest<Runnable> {
             "22".into(),
             Some(vec![PortBinding {
                 host_ip: Some("localhost".into()),
-                host_port: Some("4321".into()),
+                // Let docker pick a free port so that
+                // several tests can run at the same time
+                host_port: None,
             }]),
         );
         let container_config = bollard::container::Config {
             image: Some(spec.image.clone()),
             tty: Some(true),
+            labels: Some(DockerEnvironment::test_labels(&self.id)),
             host_config: Some(bollard::service::HostConfig {
                 binds: Some(mount_points),
                 port_bindings: Some(ports),
@@ -111,37 +154,130 @@ impl HtpTest<Runnable> {
             }),
             ..Default::default()
         };
-        let mut env = DockerEnvironment::new(&spec, container_config)
-            .await
-            .unwrap();
+        let mut env =
+            match DockerEnvironment::new(&spec, container_config, self.cancel.clone()).await {
+                Ok(env) => env,
+                Err(err) => {
+                    return Err(RunningError {
+                        msg: "Failed to create docker environment".into(),
+                        source: err,
+                        terminated: Box::new(self.clone_into()),
+                    })
+                }
+            };
+        let mut utilization = UtilizationSummary::default();
+        let container_id = env.container_id().to_string();
+        let mut stats_file = StatsFile::new(self.stats_file());
+        let run_result = {
+            let sampling = async {
+                match self.orchestrator_config.utilization_sample_interval_sec {
+                    0 => std::future::pending().await,
+                    interval => {
+                        DockerEnvironment::sample_utilization(
+                            container_id,
+                            Duration::from_secs(interval),
+                            &self.stats_sink,
+                            &mut utilization,
+                        )
+                        .await
+                    }
+                }
+            };
+            let run = with_timeout(
+                self.get_test_spec().max_duration(),
+                self.run_in(&spec, &command, &test_mount_map, &mut env),
+            );
+            tokio::select! {
+                result = run => result,
+                _ = sampling => unreachable!("sampling never finishes"),
+                _ = stats_file.follow(STATS_FILE_POLL_INTERVAL, &self.stats_sink) => {
+                    unreachable!("following never finishes")
+                }
+            }
+        };
+        stats_file.finish(&self.stats_sink).await;
+        self.utilization = utilization;
+        // The container is removed whether the test finished, failed, timed out or was cancelled.
+        // Removing it is what kills a hung test.
+        if let Err(err) = env.shutdown().await {
+            log::error!("Failed to shut down docker environment: {:?}", err);
+        }
+        // Whether the test passed or not, it was run without errors
+        self.stage_success.execution = run_result.is_ok();
+        match run_result {
+            Ok(output) if output.success() => Ok(self.terminate(TerminationReason::Finished)),
+            Ok(output) => {
+                let exit_code = output.exit_code;
+                Err(RunningError {
+                    msg: "Test script failed".into(),
+                    source: output.check().unwrap_err(),
+                    terminated: Box::new(
+                        self.terminate(TerminationReason::TestFailed { exit_code }),
+                    ),
+                })
+            }
+            Err(err) => Err(RunningError {
+                msg: "Failed to run test".into(),
+                source: err,
+                terminated: Box::new(self.clone_into()),
+            }),
+        }
+    }
+
+    fn docker_spec(&self) -> anyhow::Result<DockerSpec> {
+        let claim = self
+            .claim
+            .as_ref()
+            .ok_or_else(|| anyhow!("Test {} was never given a device", self.id))?;
+        let device = self
+            .config()
+            .devices
+            .get(&claim.device)
+            .ok_or_else(|| anyhow!("Unknown device {}", claim.device))?;
+        let device_type = self
+            .config()
+            .device_types
+            .get(&device.device_type)
+            .ok_or_else(|| anyhow!("Unknown device type {}", device.device_type))?;
+        match &device_type.classification {
+            DeviceClassification::Docker(spec) => Ok(spec.clone()),
+            // TODO support non-docker
+            DeviceClassification::Real {} => Err(anyhow!(
+                "Device {} is a real device and only docker devices are supported",
+                claim.device
+            )),
+        }
+    }
+
+    async fn run_in(
+        &self,
+        spec: &DockerSpec,
+        command: &str,
+        test_mount_map: &EnvironmentMountMap,
+        env: &mut DockerEnvironment,
+    ) -> anyhow::Result<ExecOutput> {
         for dep in self.dependencies() {
             // TODO support non-docker
-            let install_result = dep.install_on(&spec, &mut env).await;
-            if let Err(install_result) = install_result {
-                return Err(RunningError {
-                    msg: "Failed to install dep".into(),
-                    source: install_result,
-                    terminated: self.clone_into(),
-                });
-            }
+            dep.install_on(spec, env, &self.stats_sink)
+                .await
+                .context("Failed to install dep")?;
         }
-        let command = &self.get_test_spec().on_device_test_script.as_ref().unwrap();
 
-        env.exec(bollard::exec::CreateExecOptions {
-            env: Some(test_mount_map.env_vars().unwrap()),
-            working_dir: Some(spec.htp_root.join("config").to_str().unwrap().into()),
-            cmd: Some(vec![
-                "/usr/bin/env".into(),
-                "bash".into(),
-                "-c".into(),
-                // "sleep 1000".into()
-                command.clone().into(),
-            ]),
-            ..Default::default()
-        })
+        env.exec(
+            bollard::exec::CreateExecOptions {
+                env: Some(test_mount_map.env_vars()?),
+                working_dir: Some(spec.htp_root.join("config").to_str().unwrap().into()),
+                cmd: Some(vec![
+                    "/usr/bin/env".into(),
+                    "bash".into(),
+                    "-c".into(),
+                    // "sleep 1000".into()
+                    command.to_string(),
+                ]),
+                ..Default::default()
+            },
+            &self.stats_sink,
+        )
         .await
-        .unwrap();
-        env.shutdown().await.unwrap();
-        Ok(self.clone_into())
     }
 }
@@ -1,32 +1,95 @@
-use crossbeam::channel::Receiver;
+use std::sync::Arc;
 
-use crate::htp_test::{HtpTest, Terminated};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{
+    htp_test::{HtpTest, Terminated, TerminationReason},
+    resource_ledger::SharedLedger,
+};
 
 pub struct TerminatedSink {
-    input: Receiver<HtpTest<Terminated>>,
+    input: UnboundedReceiver<HtpTest<Terminated>>,
+    ledger: Arc<SharedLedger>,
+    concurrency: usize,
 }
 impl TerminatedSink {
-    pub fn new(input: Receiver<HtpTest<Terminated>>) -> Self {
-        Self { input }
+    pub fn new(
+        input: UnboundedReceiver<HtpTest<Terminated>>,
+        ledger: Arc<SharedLedger>,
+        concurrency: usize,
+    ) -> Self {
+        Self {
+            input,
+            ledger,
+            concurrency,
+        }
     }
-    pub fn desired_poll_delay(&mut self) -> tokio::time::Duration {
-        tokio::time::Duration::from_millis(100)
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            input,
+            ledger,
+            concurrency,
+        } = self;
+        super::run_worker("TerminatedSink", input, concurrency, move |to_process| {
+            Self::process_one(to_process, Arc::clone(&ledger))
+        })
+        .await
     }
-    pub fn process_one(&mut self) -> anyhow::Result<()> {
-        let Ok(to_process) = self.input.try_recv() else {
-            return Ok(());
-        };
-        println!("{:?} was terminated", to_process);
+    async fn process_one(
+        mut to_process: HtpTest<Terminated>,
+        ledger: Arc<SharedLedger>,
+    ) -> anyhow::Result<()> {
+        // The stages only see that the test's token fired, which they
+        // take to mean shutdown. Only the map knows if it was asked for.
+        if to_process.termination_reason == Some(TerminationReason::OrchestratorShutdown)
+            && to_process
+                .test_map
+                .lock()
+                .unwrap()
+                .cancel_requested(&to_process.id)
+        {
+            to_process.termination_reason = Some(TerminationReason::Cancelled);
+        }
+        log::info!(
+            "Test {} was terminated: {}",
+            to_process.id,
+            to_process
+                .termination_reason
+                .as_ref()
+                .map_or_else(|| "no reason given".into(), ToString::to_string)
+        );
+
+        // Tests that never made it through the Aquirer hold nothing
+        let (devices, apparatuses) = ledger.release_all(&to_process.id);
+        if !devices.is_empty() || !apparatuses.is_empty() {
+            log::info!(
+                "Test {} released devices {:?} and apparatuses {:?}",
+                to_process.id,
+                devices,
+                apparatuses
+            );
+        }
+
+        let completion = to_process.completion_entry();
+        if let Some(metrics) = to_process.stats_sink.metrics() {
+            let outcome = to_process
+                .termination_reason
+                .as_ref()
+                .map_or("unknown", TerminationReason::kind);
+            metrics.test_terminated(
+                &to_process.test_spec_id.0,
+                &to_process.test_spec_id.1,
+                to_process.device_type().unwrap_or("none"),
+                outcome,
+            );
+        }
+        to_process.stats_sink.complete_test(completion).await;
 
         {
             let mut map = to_process.test_map.lock().unwrap();
-            map.map.retain(|p| p.id != to_process.test_spec_id);
+            map.remove(&to_process.id);
         }
 
         Ok(())
     }
-    pub fn close(&mut self) -> anyhow::Result<()> {
-        println!("Closing");
-        Ok(())
-    }
 }
@@ -1,64 +1,85 @@
-use std::{marker::PhantomData, time::SystemTime};
-
 use anyhow::anyhow;
-use crossbeam::channel::{Receiver, Sender};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
 use crate::{
     config::Config,
-    htp_test::{Dependency, HtpTest, Queued, Terminated, Validated},
+    htp_test::{Dependency, HtpTest, Queued, Terminated, TerminationReason, Validated},
+    statistics::{Stage, StageStatus},
 };
 
 pub struct Validator {
-    input: Receiver<HtpTest<Queued>>,
-    output: Sender<HtpTest<Validated>>,
-    output_terminated: Sender<HtpTest<Terminated>>,
+    input: UnboundedReceiver<HtpTest<Queued>>,
+    output: UnboundedSender<HtpTest<Validated>>,
+    output_terminated: UnboundedSender<HtpTest<Terminated>>,
+    concurrency: usize,
 }
 impl Validator {
     pub fn new(
-        input: Receiver<HtpTest<Queued>>,
-        output: Sender<HtpTest<Validated>>,
-        output_terminated: Sender<HtpTest<Terminated>>,
+        input: UnboundedReceiver<HtpTest<Queued>>,
+        output: UnboundedSender<HtpTest<Validated>>,
+        output_terminated: UnboundedSender<HtpTest<Terminated>>,
+        concurrency: usize,
     ) -> Self {
         Self {
             input,
             output,
             output_terminated,
+            concurrency,
         }
     }
-    // Dont put a value greater than 5sec. That would be stupid
-    pub fn desired_poll_delay(&mut self) -> tokio::time::Duration {
-        tokio::time::Duration::from_millis(100)
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            input,
+            output,
+            output_terminated,
+            concurrency,
+        } = self;
+        super::run_worker("Validator", input, concurrency, move |to_validate| {
+            Self::process_one(to_validate, output.clone(), output_terminated.clone())
+        })
+        .await
     }
-    pub fn process_one(&mut self) -> anyhow::Result<()> {
-        let Ok(mut to_validate) = self.input.try_recv() else {
+    async fn process_one(
+        to_validate: HtpTest<Queued>,
+        output: UnboundedSender<HtpTest<Validated>>,
+        output_terminated: UnboundedSender<HtpTest<Terminated>>,
+    ) -> anyhow::Result<()> {
+        let Some(to_validate) = super::forward_if_cancelled(to_validate, &output_terminated)?
+        else {
             return Ok(());
         };
-
-        to_validate.stats_sink.write("validation", "started");
+        to_validate
+            .stats_sink
+            .write(Stage::Validation, StageStatus::Started);
         let validated = to_validate.validate();
         //TODO(is_ok) is wrong
         match validated {
             Ok(mut validated) => {
+                validated.stage_success.validation = true;
                 validated
                     .stats_sink
-                    .write("validation", "finished successfully");
-
-                self.output.send(validated)?
+                    .write(Stage::Validation, StageStatus::Finished);
+                log::debug!("Test {} validated", validated.id);
+                output.send(validated)?
             }
             Err(mut validate_error) => {
+                validate_error.terminated.stats_sink.write(
+                    Stage::Validation,
+                    StageStatus::Failed(format!(
+                        "{}: {:#}",
+                        validate_error.msg, validate_error.source
+                    )),
+                );
+                log::error!("Err: {:?}", validate_error);
                 validate_error
                     .terminated
-                    .stats_sink
-                    .write("validation", "failed");
-                log::error!("Err: {:?}", validate_error);
-                self.output_terminated.send(validate_error.terminated)?
+                    .termination_reason
+                    .get_or_insert_with(|| {
+                        TerminationReason::from_error(&validate_error.msg, &validate_error.source)
+                    });
+                output_terminated.send(*validate_error.terminated)?
             }
         };
-        println!("Validated");
-        Ok(())
-    }
-    pub fn close(&mut self) -> anyhow::Result<()> {
-        println!("Closing");
         Ok(())
     }
 }
@@ -69,7 +90,7 @@ pub struct ValidateError {
     msg: String,
     #[source]
     source: anyhow::Error,
-    terminated: HtpTest<Terminated>,
+    terminated: Box<HtpTest<Terminated>>,
 }
 
 impl HtpTest<Queued> {
@@ -88,7 +109,7 @@ impl HtpTest<Queued> {
                         return Err(ValidateError {
                             msg: "Unable to find test group for test specification".into(),
                             source: anyhow!("Config creation"),
-                            terminated: self.clone_into(),
+                            terminated: Box::new(self.clone_into()),
                         });
                     }
                 };
@@ -99,15 +120,17 @@ impl HtpTest<Queued> {
                         return Err(ValidateError {
                             msg: "Unable to find test specification in test group".into(),
                             source: anyhow!("Config creation"),
-                            terminated: self.clone_into(),
+                            terminated: Box::new(self.clone_into()),
                         });
                     }
                 };
                 // prepare dependencies
                 let mut dependencies = Vec::new();
                 for dep_name in test_specification.dependencies.keys() {
-                    self.stats_sink
-                        .write("validation", format!("Creating dependency on {}", dep_name));
+                    self.stats_sink.write(
+                        Stage::Validation,
+                        StageStatus::Progress(format!("Creating dependency on {}", dep_name)),
+                    );
                     let dep_spec = config.dependencies.get(dep_name);
                     let dep_spec = match dep_spec {
                         Some(k) => k,
@@ -115,7 +138,7 @@ impl HtpTest<Queued> {
                             return Err(ValidateError {
                                 msg: "Unable to find specified dependency".into(),
                                 source: anyhow!("Config creation"),
-                                terminated: self.clone_into(),
+                                terminated: Box::new(self.clone_into()),
                             });
                         }
                     };
@@ -126,7 +149,7 @@ impl HtpTest<Queued> {
                             return Err(ValidateError {
                                 msg: "Unable to find create a dependency".into(),
                                 source: err,
-                                terminated: self.clone_into(),
+                                terminated: Box::new(self.clone_into()),
                             });
                         }
                     }
@@ -137,7 +160,7 @@ impl HtpTest<Queued> {
                             msg: "Dependency is trying to run on a device_type that doesn't exist"
                                 .into(),
                             source: anyhow!("Config creation"),
-                            terminated: self.clone_into(),
+                            terminated: Box::new(self.clone_into()),
                         });
                     }
                 }
@@ -150,7 +173,7 @@ impl HtpTest<Queued> {
             Err(err) => Err(ValidateError {
                 msg: "Config creation failed".into(),
                 source: err,
-                terminated: self.clone_into(),
+                terminated: Box::new(self.clone_into()),
             }),
         }
     }
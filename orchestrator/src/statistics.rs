@@ -1,64 +1,17 @@
 use std::{
-    collections::HashMap,
     fmt::Debug,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
-use elasticsearch::Elasticsearch;
-use serde::Serialize;
-
-use crate::{config::tests::TestSpecification, htp_test::TestID};
-// #[derive(Serialize)]
-// struct LokiStream {
-//     stream: HashMap<String, String>,
-//     values: Vec<[String; 2]>,
-// }
-
-// #[derive(Serialize)]
-// struct LokiRequest {
-//     streams: Vec<LokiStream>,
-// }
-
-// struct LokiLogger {
-//     url: String,
-//     initial_labels: Option<HashMap<String, String>>,
-//     client: reqwest::Client,
-// }
-
-// impl LokiLogger {
-//     fn time_offset_since(start: SystemTime) -> anyhow::Result<String> {
-//         let since_start = start.duration_since(UNIX_EPOCH)?;
-//         let time_ns = since_start.as_nanos().to_string();
-//         Ok(time_ns)
-//     }
-//     fn make_request(
-//         message: String,
-//         labels: HashMap<String, String>,
-//     ) -> anyhow::Result<LokiRequest> {
-//         let start = SystemTime::now();
-//         let time_ns = Self::time_offset_since(start)?;
-//         let loki_request = LokiRequest {
-//             streams: vec![LokiStream {
-//                 stream: labels,
-//                 values: vec![[time_ns, message], ["chicken".into(), "butt".into()]],
-//             }],
-//         };
-//         Ok(loki_request)
-//     }
-//     fn log_to_loki(&self, message: String, labels: HashMap<String, String>) -> anyhow::Result<()> {
-//         let client = self.client.clone();
-//         let url = self.url.clone();
-
-//         let loki_request = Self::make_request(message, labels)?;
-//         tokio::spawn(async move {
-//             if let Err(e) = client.post(url).json(&loki_request).send().await {
-//                 eprintln!("{:?}", e);
-//             };
-//         });
-//         Ok(())
-//     }
-// }
-//
+use futures::Future;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::tests::TestSpecification, loki::LokiStream, metrics::Metrics, stats_sink::StatsSink,
+};
 #[derive(Clone, Debug, Serialize)]
 pub struct TestRegistrationEntry {
     t_id: String,
@@ -68,49 +21,184 @@ pub struct TestRegistrationEntry {
 
 #[derive(Clone, Debug, Serialize)]
 pub struct TestCompletionEntry {
-    t_id: String,
-    test_name: String,
-    creation_time: chrono::DateTime<chrono::Utc>,
-    execution_start_time: chrono::DateTime<chrono::Utc>,
-    termination_time: chrono::DateTime<chrono::Utc>,
-    passed: bool,
-    stage_success: TestStageSucessEntry,
-    test_config: TestSpecification,
-    dependencies: Vec<String>,
+    pub t_id: String,
+    pub test_name: String,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+    // None if the test never started running
+    pub execution_start_time: Option<chrono::DateTime<chrono::Utc>>,
+    pub termination_time: chrono::DateTime<chrono::Utc>,
+    pub passed: bool,
+    pub termination_reason: String,
+    pub stage_success: TestStageSucessEntry,
+    // None if the test failed before its spec could be read
+    pub test_config: Option<TestSpecification>,
+    pub dependencies: Vec<String>,
+    // None if the container's utilization was never sampled
+    pub peak_ram_usage_megabytes: Option<u32>,
+    pub mean_cpu_load: Option<f32>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct TestStageSucessEntry {
-    validation: bool,
-    dependency_building: bool,
-    resource_aquisition: bool,
-    execution: bool,
+    pub validation: bool,
+    pub dependency_building: bool,
+    pub resource_aquisition: bool,
+    pub execution: bool,
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub struct UtilizationEntry {
+    #[serde(rename = "t_id")]
     id: String,
     timestamp: chrono::DateTime<chrono::Utc>,
+    // Percent of one core, so 250.0 is two and a half cores
     cpu_load: f32,
     ram_usage_megabytes: u32,
 }
 
+// Running totals of a test's utilization samples
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UtilizationSummary {
+    samples: u32,
+    cpu_load_total: f64,
+    peak_ram_usage_megabytes: u32,
+}
+
+impl UtilizationSummary {
+    pub fn record(&mut self, cpu_load: f32, ram_usage_megabytes: u32) {
+        self.samples += 1;
+        self.cpu_load_total += cpu_load as f64;
+        self.peak_ram_usage_megabytes = self.peak_ram_usage_megabytes.max(ram_usage_megabytes);
+    }
+    pub fn mean_cpu_load(&self) -> Option<f32> {
+        (self.samples > 0).then(|| (self.cpu_load_total / self.samples as f64) as f32)
+    }
+    pub fn peak_ram_usage_megabytes(&self) -> Option<u32> {
+        (self.samples > 0).then_some(self.peak_ram_usage_megabytes)
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct LogsEntry {
+    #[serde(rename = "t_id")]
     id: String,
     timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(rename = "log_line")]
     log_num: u64,
+    #[serde(rename = "data")]
     log_msg: String,
     is_stderr: bool,
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub struct StatsEntry<T: Clone + Debug + Serialize> {
+    #[serde(rename = "t_id")]
     id: String,
     timestamp: chrono::DateTime<chrono::Utc>,
     data: T,
 }
 
+// The stages that record their progress in the stage_events index.
+// The names stored in the index predate this enum, spelling included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stage {
+    #[serde(rename = "validation")]
+    Validation,
+    #[serde(rename = "preperation")]
+    Preparation,
+    #[serde(rename = "aquisition")]
+    Acquisition,
+    #[serde(rename = "running")]
+    Running,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Validation => "validation",
+            Stage::Preparation => "preperation",
+            Stage::Acquisition => "aquisition",
+            Stage::Running => "running",
+        }
+    }
+}
+
+// Where a test is within a stage
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "detail", rename_all = "snake_case")]
+pub enum StageStatus {
+    Started,
+    // Something worth noting happened part way through the stage
+    Progress(String),
+    Finished,
+    Failed(String),
+}
+
+// One document in the stage_events index. One is written
+// every time a test moves through a stage.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StageEventEntry {
+    pub t_id: String,
+    pub stage: Stage,
+    #[serde(flatten)]
+    pub status: StageStatus,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    // Orders events that share a timestamp
+    pub event_num: u64,
+}
+
+// The time a test spent in one stage
+#[derive(Clone, Debug, PartialEq)]
+pub struct StageSpan {
+    pub stage: Stage,
+    pub started: chrono::DateTime<chrono::Utc>,
+    // None if the stage never finished or failed
+    pub ended: Option<chrono::DateTime<chrono::Utc>>,
+    // Finished or Failed once the stage has ended
+    pub outcome: Option<StageStatus>,
+    pub progress: Vec<String>,
+}
+
+impl StageSpan {
+    pub fn duration(&self) -> Option<chrono::Duration> {
+        Some(self.ended? - self.started)
+    }
+}
+
+// Folds a test's stage events into one span per stage, in the order they were entered.
+// Events for a stage that was never started are ignored.
+pub fn build_timeline(mut events: Vec<StageEventEntry>) -> Vec<StageSpan> {
+    events.sort_by_key(|event| (event.timestamp, event.event_num));
+    let mut spans: Vec<StageSpan> = Vec::new();
+    for event in events {
+        if event.status == StageStatus::Started {
+            spans.push(StageSpan {
+                stage: event.stage,
+                started: event.timestamp,
+                ended: None,
+                outcome: None,
+                progress: Vec::new(),
+            });
+            continue;
+        }
+        let Some(span) = spans
+            .iter_mut()
+            .rev()
+            .find(|span| span.stage == event.stage)
+        else {
+            continue;
+        };
+        match event.status {
+            StageStatus::Progress(detail) => span.progress.push(detail),
+            outcome => {
+                span.ended = Some(event.timestamp);
+                span.outcome = Some(outcome);
+            }
+        }
+    }
+    spans
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct DependenciesEntry {
     d_id: String,
@@ -121,14 +209,21 @@ pub struct DependenciesEntry {
     fs_root: String,
 }
 
-// This was written so that it might
-// be easy to one day support multiple databases
+// Builds the documents described in docs/data_output.md for one test
+// and hands them to whichever StatsSink the orchestrator was configured with.
+// Failing to record statistics is logged but never fails the test.
 #[derive(Debug, Clone)]
 pub struct DbWrapper {
     ty: WrapperType,
     id: String,
-    client: Elasticsearch,
-    log_line_number: u64,
+    sink: Arc<dyn StatsSink>,
+    // Test output is also shipped here if Loki is set up
+    loki: Option<LokiStream>,
+    metrics: Option<Arc<Metrics>>,
+    // Shared between clones so that everything logged
+    // for one test is numbered in a single sequence
+    log_line_number: Arc<AtomicU64>,
+    event_number: Arc<AtomicU64>,
 }
 #[derive(Debug, Clone)]
 pub enum WrapperType {
@@ -136,49 +231,228 @@ pub enum WrapperType {
     Dependency,
 }
 impl DbWrapper {
-    pub fn new_elasticsearch(ty: WrapperType, id: String, client: Elasticsearch) -> Self {
+    pub fn new(ty: WrapperType, id: String, sink: Arc<dyn StatsSink>) -> Self {
         Self {
             ty,
             id,
-            client,
-            log_line_number: 0,
+            sink,
+            loki: None,
+            metrics: None,
+            log_line_number: Arc::new(AtomicU64::new(0)),
+            event_number: Arc::new(AtomicU64::new(0)),
         }
     }
+    pub fn with_loki(mut self, loki: LokiStream) -> Self {
+        self.loki = Some(loki);
+        self
+    }
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_deref()
+    }
+    pub fn ty(&self) -> &WrapperType {
+        &self.ty
+    }
+    // Labels everything shipped to Loki from now on, e.g. with the aquired device
+    pub fn set_label<K: Into<String>, V: Into<String>>(&self, key: K, value: V) {
+        if let Some(loki) = &self.loki {
+            loki.set_label(key, value);
+        }
+    }
+    fn submit<T: Serialize + Debug>(
+        &self,
+        index: &'static str,
+        entry: T,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let indexed = serde_json::to_value(&entry)
+            .map_err(anyhow::Error::from)
+            .map(|document| self.sink.index(index, document));
+        let id = self.id.clone();
+        let metrics = self.metrics.clone();
+        async move {
+            let result = match indexed {
+                Ok(indexed) => indexed.await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                if let Some(metrics) = metrics {
+                    metrics.stats_sink_error(index);
+                }
+                log::error!("Failed to index {} entry for {}: {:?}", index, id, err);
+            }
+        }
+    }
+    // Records a stage transition.
+    // This does not wait for the event to be indexed so that
+    // a slow database never holds up the stages.
+    pub fn write(&self, stage: Stage, status: StageStatus) {
+        if status == StageStatus::Started {
+            self.set_label("stage", stage.as_str());
+        }
+        let entry = StageEventEntry {
+            t_id: self.id.clone(),
+            stage,
+            status,
+            timestamp: chrono::offset::Utc::now(),
+            event_num: self.event_number.fetch_add(1, Ordering::SeqCst),
+        };
+        tokio::spawn(self.submit("stage_events", entry));
+    }
+    // Rebuilds this test's stage timeline from the stage_events index
+    pub async fn timeline(&self) -> anyhow::Result<Vec<StageSpan>> {
+        let mut events = Vec::new();
+        for document in self.sink.find_by_test("stage_events", &self.id).await? {
+            events.push(serde_json::from_value(document)?);
+        }
+        Ok(build_timeline(events))
+    }
     pub async fn register_test<S: Into<String>>(&self, test_name: S) {
         let entry = TestRegistrationEntry {
             t_id: self.id.clone(),
             test_name: test_name.into(),
             creation_time: chrono::offset::Utc::now(),
         };
-        let response = self
-            .client
-            .index(elasticsearch::IndexParts::Index("test_registration"))
-            .body(entry)
-            .send()
-            .await;
-
-        if response.is_err() {
-            panic!("TODO(ZACK) LOG THIS TO META DB")
-        }
+        self.submit("test_registration", entry).await
     }
-    pub async fn log<S: Into<String>>(&mut self, is_stderr: bool, msg: S) {
-        let entry = LogsEntry {
+    pub async fn complete_test(&self, entry: TestCompletionEntry) {
+        self.submit("tests", entry).await
+    }
+    pub async fn utilization(&self, cpu_load: f32, ram_usage_megabytes: u32) {
+        let entry = UtilizationEntry {
             id: self.id.clone(),
             timestamp: chrono::offset::Utc::now(),
-            log_num: self.log_line_number,
-            log_msg: msg.into(),
+            cpu_load,
+            ram_usage_megabytes,
+        };
+        self.submit("utilization", entry).await
+    }
+    // Something the test itself wanted recorded, see stats_file.rs
+    pub async fn custom_stats(
+        &self,
+        data: serde_json::Value,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    ) {
+        let entry = StatsEntry {
+            id: self.id.clone(),
+            timestamp,
+            data,
+        };
+        self.submit("stats", entry).await
+    }
+    pub async fn log<S: Into<String>>(&self, is_stderr: bool, msg: S) {
+        self.log_at(is_stderr, msg, chrono::offset::Utc::now())
+            .await
+    }
+    pub async fn log_at<S: Into<String>>(
+        &self,
+        is_stderr: bool,
+        msg: S,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    ) {
+        let msg = msg.into();
+        if let Some(loki) = &self.loki {
+            loki.send(is_stderr, msg.clone(), timestamp);
+        }
+        let entry = LogsEntry {
+            id: self.id.clone(),
+            timestamp,
+            log_num: self.log_line_number.fetch_add(1, Ordering::SeqCst),
+            log_msg: msg,
             is_stderr,
         };
-        let response = self
-            .client
-            .index(elasticsearch::IndexParts::Index("logs"))
-            .body(entry)
-            .send()
-            .await;
-
-        if response.is_err() {
-            panic!("TODO(ZACK) LOG THIS TO META DB")
+        self.submit("logs", entry).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats_sink::MemorySink;
+
+    fn event(stage: Stage, status: StageStatus, secs: i64, event_num: u64) -> StageEventEntry {
+        StageEventEntry {
+            t_id: "run".into(),
+            stage,
+            status,
+            timestamp: chrono::TimeZone::timestamp_opt(&chrono::Utc, secs, 0).unwrap(),
+            event_num,
         }
-        self.log_line_number += 1;
+    }
+
+    #[test]
+    fn test_stage_event_serialization() {
+        let failed = event(
+            Stage::Running,
+            StageStatus::Failed("exit code 1".into()),
+            0,
+            0,
+        );
+        let value = serde_json::to_value(&failed).unwrap();
+        assert_eq!(value["stage"], "running");
+        assert_eq!(value["status"], "failed");
+        assert_eq!(value["detail"], "exit code 1");
+        let parsed: StageEventEntry = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed, failed);
+
+        let started =
+            serde_json::to_value(event(Stage::Running, StageStatus::Started, 0, 0)).unwrap();
+        assert_eq!(started["status"], "started");
+    }
+
+    #[test]
+    fn test_build_timeline() {
+        // Out of order, as they may come back from the database
+        let timeline = build_timeline(vec![
+            event(Stage::Preparation, StageStatus::Started, 5, 3),
+            event(Stage::Validation, StageStatus::Finished, 5, 2),
+            event(Stage::Validation, StageStatus::Started, 1, 0),
+            event(Stage::Validation, StageStatus::Progress("dep".into()), 2, 1),
+            event(
+                Stage::Preparation,
+                StageStatus::Failed("build".into()),
+                65,
+                4,
+            ),
+        ]);
+        assert_eq!(timeline.len(), 2);
+        assert_eq!(timeline[0].stage, Stage::Validation);
+        assert_eq!(timeline[0].progress, vec!["dep"]);
+        assert_eq!(timeline[0].duration(), Some(chrono::Duration::seconds(4)));
+        assert_eq!(
+            timeline[1].outcome,
+            Some(StageStatus::Failed("build".into()))
+        );
+        assert_eq!(timeline[1].duration(), Some(chrono::Duration::seconds(60)));
+    }
+
+    #[test]
+    fn test_utilization_summary() {
+        let mut summary = UtilizationSummary::default();
+        assert_eq!(summary.mean_cpu_load(), None);
+        assert_eq!(summary.peak_ram_usage_megabytes(), None);
+        summary.record(50.0, 300);
+        summary.record(150.0, 900);
+        summary.record(100.0, 600);
+        assert_eq!(summary.mean_cpu_load(), Some(100.0));
+        assert_eq!(summary.peak_ram_usage_megabytes(), Some(900));
+    }
+
+    #[tokio::test]
+    async fn test_timeline_round_trip() {
+        let sink = MemorySink::default();
+        let db = DbWrapper::new(WrapperType::Test, "run".into(), Arc::new(sink.clone()));
+        db.write(Stage::Validation, StageStatus::Started);
+        db.write(Stage::Validation, StageStatus::Finished);
+        db.log(false, "hello").await;
+        // write() indexes in the background
+        tokio::task::yield_now().await;
+
+        assert_eq!(sink.documents("logs")[0]["data"], "hello");
+        let timeline = db.timeline().await.unwrap();
+        assert_eq!(timeline.len(), 1);
+        assert_eq!(timeline[0].outcome, Some(StageStatus::Finished));
     }
 }
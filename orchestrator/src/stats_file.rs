@@ -0,0 +1,135 @@
+use std::{io::SeekFrom, path::PathBuf, time::Duration};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::{environment::LineSplitter, statistics::DbWrapper};
+
+// Tests record their own statistics (frame latency, jitter, ...) by appending
+// one json value per line to the file named by HTP_STATS. The orchestrator
+// follows the file from the host side while the test runs, so this works for
+// any environment that maps the file back onto the host.
+pub struct StatsFile {
+    path: PathBuf,
+    offset: u64,
+    lines: LineSplitter,
+}
+
+impl StatsFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            offset: 0,
+            lines: LineSplitter::default(),
+        }
+    }
+    // Forwards every complete line appended since the last call.
+    // Returns how many values were forwarded.
+    pub async fn forward_new(&mut self, logs: &DbWrapper) -> anyhow::Result<usize> {
+        let mut file = tokio::fs::File::open(&self.path).await?;
+        file.seek(SeekFrom::Start(self.offset)).await?;
+        let mut appended = Vec::new();
+        file.read_to_end(&mut appended).await?;
+        self.offset += appended.len() as u64;
+        let received = chrono::offset::Utc::now();
+        let mut forwarded = 0;
+        for line in self.lines.push(&appended) {
+            forwarded += Self::forward_line(&line, received, logs).await as usize;
+        }
+        Ok(forwarded)
+    }
+    // Checks for new values every `interval`.
+    // This never returns, it is meant to be dropped once the test is done.
+    pub async fn follow(&mut self, interval: Duration, logs: &DbWrapper) {
+        let mut poll = tokio::time::interval(interval);
+        loop {
+            poll.tick().await;
+            if let Err(err) = self.forward_new(logs).await {
+                log::warn!("Unable to read stats file {:?}: {:?}", self.path, err);
+            }
+        }
+    }
+    // Forwards whatever is left once the test has stopped writing,
+    // including a last line without a trailing newline
+    pub async fn finish(&mut self, logs: &DbWrapper) {
+        if let Err(err) = self.forward_new(logs).await {
+            log::warn!("Unable to read stats file {:?}: {:?}", self.path, err);
+        }
+        if let Some(line) = self.lines.finish() {
+            Self::forward_line(&line, chrono::offset::Utc::now(), logs).await;
+        }
+    }
+    async fn forward_line(
+        line: &str,
+        received: chrono::DateTime<chrono::Utc>,
+        logs: &DbWrapper,
+    ) -> bool {
+        if line.trim().is_empty() {
+            return false;
+        }
+        match serde_json::from_str(line) {
+            Ok(value) => {
+                logs.custom_stats(value, received).await;
+                true
+            }
+            Err(err) => {
+                // Shown alongside the test's own output
+                logs.log(
+                    true,
+                    format!("HTP_STATS: ignoring invalid json ({}): {}", err, line),
+                )
+                .await;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::Arc};
+
+    use super::*;
+    use crate::{statistics::WrapperType, stats_sink::MemorySink};
+
+    #[tokio::test]
+    async fn test_forwards_appended_values() {
+        let dir = std::env::temp_dir().join(format!("htp-stats-file-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stats.jsonl");
+        let mut writer = std::fs::File::create(&path).unwrap();
+
+        let sink = MemorySink::default();
+        let logs = DbWrapper::new(WrapperType::Test, "run".into(), Arc::new(sink.clone()));
+        let mut stats = StatsFile::new(path);
+
+        writer
+            .write_all(b"{\"latency_ms\": 12}\n{\"latency")
+            .unwrap();
+        assert_eq!(stats.forward_new(&logs).await.unwrap(), 1);
+        writer
+            .write_all(b"_ms\": 15}\nnot json\n{\"jitter_ms\": 3}")
+            .unwrap();
+        assert_eq!(stats.forward_new(&logs).await.unwrap(), 1);
+        stats.finish(&logs).await;
+
+        let values: Vec<_> = sink
+            .documents("stats")
+            .into_iter()
+            .map(|doc| {
+                assert_eq!(doc["t_id"], "run");
+                doc["data"].clone()
+            })
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                serde_json::json!({ "latency_ms": 12 }),
+                serde_json::json!({ "latency_ms": 15 }),
+                serde_json::json!({ "jitter_ms": 3 }),
+            ]
+        );
+        assert_eq!(sink.documents("logs").len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use elasticsearch::{http::transport::Transport, Elasticsearch, SearchParts};
+use futures::{future::BoxFuture, FutureExt};
+use serde_json::{json, Value};
+
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+
+use crate::{
+    bulk_writer::{BulkMessage, BulkSettings, BulkWriter, ElasticsearchBulk, SpooledDocument},
+    config::orchestrator_config::{OrchestratorConfig, StatsBackend},
+    metrics::Metrics,
+};
+
+// Somewhere to keep the documents described in docs/data_output.md.
+// DbWrapper builds the documents; a StatsSink only has to store them
+// by index name and find them again by test id.
+pub trait StatsSink: Debug + Send + Sync {
+    fn index(&self, index: &'static str, document: Value)
+        -> BoxFuture<'static, anyhow::Result<()>>;
+    // Every document in `index` whose t_id is `t_id`, in no particular order
+    fn find_by_test(
+        &self,
+        index: &'static str,
+        t_id: &str,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<Value>>>;
+    // Resolves once everything indexed so far has been stored
+    // (or put somewhere it will not be lost)
+    fn flush(&self) -> BoxFuture<'static, ()> {
+        async {}.boxed()
+    }
+}
+
+// Must be called from within the tokio runtime
+pub fn from_config(
+    config: &OrchestratorConfig,
+    metrics: Arc<Metrics>,
+) -> anyhow::Result<Arc<dyn StatsSink>> {
+    Ok(match &config.stats_backend {
+        StatsBackend::Elasticsearch => Arc::new(ElasticsearchSink::new(
+            &config.elastic_addr,
+            config.htp_folder_root.join("stats_spool.jsonl"),
+            Some(metrics),
+        )?),
+        StatsBackend::JsonLines { folder } => Arc::new(JsonLinesSink::new(config.resolve(folder))?),
+        StatsBackend::Memory => Arc::new(MemorySink::default()),
+    })
+}
+
+// Documents are handed to a BulkWriter, see bulk_writer.rs
+#[derive(Debug, Clone)]
+pub struct ElasticsearchSink {
+    client: Elasticsearch,
+    writer: UnboundedSender<BulkMessage>,
+}
+
+impl ElasticsearchSink {
+    // Starts the background writer, which spools to `spool_path` while the cluster is down.
+    // Documents that are spooled or dropped are counted in `metrics`.
+    pub fn new(
+        addr: &str,
+        spool_path: PathBuf,
+        metrics: Option<Arc<Metrics>>,
+    ) -> anyhow::Result<Self> {
+        let transport = Transport::single_node(addr)?;
+        let client = Elasticsearch::new(transport);
+        let (writer, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let bulk = ElasticsearchBulk {
+            client: client.clone(),
+            metrics: metrics.clone(),
+        };
+        let mut bulk_writer = BulkWriter::new(receiver, bulk, spool_path, BulkSettings::default());
+        if let Some(metrics) = metrics {
+            bulk_writer = bulk_writer.with_metrics(metrics);
+        }
+        tokio::spawn(bulk_writer.run());
+        Ok(Self { client, writer })
+    }
+}
+
+impl StatsSink for ElasticsearchSink {
+    fn index(
+        &self,
+        index: &'static str,
+        document: Value,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let document = SpooledDocument {
+            index: index.into(),
+            document,
+        };
+        let sent = self
+            .writer
+            .send(BulkMessage::Document(document))
+            .map_err(|_| anyhow::anyhow!("The statistics writer has stopped"));
+        async move { sent }.boxed()
+    }
+    fn find_by_test(
+        &self,
+        index: &'static str,
+        t_id: &str,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<Value>>> {
+        let client = self.client.clone();
+        let query = json!({
+            "query": { "term": { "t_id.keyword": t_id } },
+            "size": 10000,
+        });
+        async move {
+            let response = client
+                .search(SearchParts::Index(&[index]))
+                .body(query)
+                .send()
+                .await?
+                .error_for_status_code()?;
+            let mut body: Value = response.json().await?;
+            let hits = body["hits"]["hits"]
+                .as_array_mut()
+                .context("Malformed search response")?;
+            Ok(hits.iter_mut().map(|hit| hit["_source"].take()).collect())
+        }
+        .boxed()
+    }
+    fn flush(&self) -> BoxFuture<'static, ()> {
+        let (reply, flushed) = oneshot::channel();
+        let _ = self.writer.send(BulkMessage::Flush(reply));
+        async move {
+            let _ = flushed.await;
+        }
+        .boxed()
+    }
+}
+
+// Appends each index to <folder>/<index>.jsonl, one document per line.
+// Meant for labs without an Elasticsearch cluster and for debugging.
+#[derive(Debug)]
+pub struct JsonLinesSink {
+    folder: PathBuf,
+    files: Arc<Mutex<HashMap<&'static str, File>>>,
+}
+
+impl JsonLinesSink {
+    pub fn new(folder: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&folder)
+            .with_context(|| format!("Creating stats folder {:?}", folder))?;
+        Ok(Self {
+            folder,
+            files: Arc::default(),
+        })
+    }
+    fn path(&self, index: &str) -> PathBuf {
+        self.folder.join(format!("{}.jsonl", index))
+    }
+}
+
+impl StatsSink for JsonLinesSink {
+    fn index(
+        &self,
+        index: &'static str,
+        document: Value,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let path = self.path(index);
+        let files = Arc::clone(&self.files);
+        async move {
+            let mut line = serde_json::to_string(&document)?;
+            line.push('\n');
+            // Held while writing so that lines from different tests never interleave
+            let mut files = files.lock().unwrap();
+            let file = match files.entry(index) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => entry.insert(
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&path)
+                        .with_context(|| format!("Opening {:?}", path))?,
+                ),
+            };
+            file.write_all(line.as_bytes())?;
+            Ok(())
+        }
+        .boxed()
+    }
+    fn find_by_test(
+        &self,
+        index: &'static str,
+        t_id: &str,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<Value>>> {
+        let path = self.path(index);
+        let t_id = t_id.to_string();
+        async move {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(err) => return Err(err).with_context(|| format!("Opening {:?}", path)),
+            };
+            let mut documents = Vec::new();
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                // A torn final line is only possible if we crashed mid write
+                let Ok(document) = serde_json::from_str::<Value>(&line) else {
+                    continue;
+                };
+                if document["t_id"] == t_id.as_str() {
+                    documents.push(document);
+                }
+            }
+            Ok(documents)
+        }
+        .boxed()
+    }
+}
+
+// Keeps everything in memory so that unit tests can look at what was recorded
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    documents: Arc<Mutex<Vec<(&'static str, Value)>>>,
+}
+
+impl MemorySink {
+    // Everything written to `index` so far, oldest first
+    pub fn documents(&self, index: &str) -> Vec<Value> {
+        let documents = self.documents.lock().unwrap();
+        documents
+            .iter()
+            .filter(|(name, _)| *name == index)
+            .map(|(_, document)| document.clone())
+            .collect()
+    }
+}
+
+impl StatsSink for MemorySink {
+    fn index(
+        &self,
+        index: &'static str,
+        document: Value,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        self.documents.lock().unwrap().push((index, document));
+        async { Ok(()) }.boxed()
+    }
+    fn find_by_test(
+        &self,
+        index: &'static str,
+        t_id: &str,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<Value>>> {
+        let found = self
+            .documents(index)
+            .into_iter()
+            .filter(|document| document["t_id"] == t_id)
+            .collect();
+        async { Ok(found) }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_json_lines_sink() {
+        let folder = std::env::temp_dir().join(format!("htp-stats-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        let sink = JsonLinesSink::new(folder.clone()).unwrap();
+
+        sink.index("logs", json!({ "t_id": "a", "data": "one" }))
+            .await
+            .unwrap();
+        sink.index("logs", json!({ "t_id": "b", "data": "two" }))
+            .await
+            .unwrap();
+        sink.index("logs", json!({ "t_id": "a", "data": "three" }))
+            .await
+            .unwrap();
+
+        let found = sink.find_by_test("logs", "a").await.unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1]["data"], "three");
+        assert!(sink.find_by_test("tests", "a").await.unwrap().is_empty());
+
+        // Survives the orchestrator restarting
+        let reopened = JsonLinesSink::new(folder.clone()).unwrap();
+        assert_eq!(reopened.find_by_test("logs", "b").await.unwrap().len(), 1);
+        let _ = std::fs::remove_dir_all(&folder);
+    }
+}